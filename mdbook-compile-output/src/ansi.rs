@@ -0,0 +1,288 @@
+//! ANSI escape sequences in captured output.
+
+use serde::Deserialize;
+use std::fmt::Write;
+
+/// What happens to escape sequences in the output, set with the `ansi` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnsiMode {
+    /// Remove them, leaving plain text.
+    #[default]
+    Strip,
+    /// Force colored cargo output and turn it into styled HTML. Only the
+    /// HTML renderer gets this; every other renderer falls back to `strip`.
+    Html,
+}
+
+/// Removes all escape sequences from `text`.
+pub fn strip(text: &str) -> String {
+    match strip_ansi_escapes::strip(text) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => text.to_string(),
+    }
+}
+
+/// The 16 standard terminal colors, as the common xterm palette.
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// The text attributes set by SGR (`ESC [ ... m`) sequences.
+#[derive(Debug, Default)]
+struct Attributes {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    foreground: Option<String>,
+    background: Option<String>,
+}
+
+impl Attributes {
+    fn css(&self) -> String {
+        let mut css = String::new();
+        if self.bold {
+            css.push_str("font-weight:bold;");
+        }
+        if self.dim {
+            css.push_str("opacity:0.7;");
+        }
+        if self.italic {
+            css.push_str("font-style:italic;");
+        }
+        if self.underline {
+            css.push_str("text-decoration:underline;");
+        }
+        if let Some(color) = &self.foreground {
+            let _ = write!(css, "color:{color};");
+        }
+        if let Some(color) = &self.background {
+            let _ = write!(css, "background-color:{color};");
+        }
+        css
+    }
+
+    fn apply(&mut self, params: &str) {
+        let mut codes = params
+            .split(';')
+            .map(|code| code.parse::<u16>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Attributes::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.foreground = Some(PALETTE[code as usize - 30].to_string()),
+                90..=97 => self.foreground = Some(PALETTE[code as usize - 90 + 8].to_string()),
+                40..=47 => self.background = Some(PALETTE[code as usize - 40].to_string()),
+                100..=107 => self.background = Some(PALETTE[code as usize - 100 + 8].to_string()),
+                39 => self.foreground = None,
+                49 => self.background = None,
+                38 => self.foreground = extended_color(&mut codes),
+                48 => self.background = extended_color(&mut codes),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Reads the rest of a `38;5;n` or `38;2;r;g;b` color.
+fn extended_color(codes: &mut impl Iterator<Item = u16>) -> Option<String> {
+    match codes.next()? {
+        5 => {
+            let index = codes.next()?;
+            Some(match index {
+                0..=15 => PALETTE[index as usize].to_string(),
+                16..=231 => {
+                    let level = |n: u16| if n == 0 { 0 } else { 55 + n * 40 };
+                    let n = index - 16;
+                    format!(
+                        "rgb({},{},{})",
+                        level(n / 36),
+                        level(n / 6 % 6),
+                        level(n % 6)
+                    )
+                }
+                _ => {
+                    let gray = 8 + (index.min(255) - 232) * 10;
+                    format!("rgb({gray},{gray},{gray})")
+                }
+            })
+        }
+        2 => {
+            let (r, g, b) = (codes.next()?, codes.next()?, codes.next()?);
+            Some(format!("rgb({r},{g},{b})"))
+        }
+        _ => None,
+    }
+}
+
+/// Turns colored terminal output into HTML-escaped text with `<span>`s
+/// carrying the colors as inline styles. Escape sequences other than SGR
+/// are dropped.
+pub fn to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut attributes = Attributes::default();
+    // The style of the open span; spans are only opened once text follows,
+    // so runs of escape sequences do not leave empty spans behind.
+    let mut open = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            let css = attributes.css();
+            if css != open {
+                if !open.is_empty() {
+                    html.push_str("</span>");
+                }
+                if !css.is_empty() {
+                    let _ = write!(html, "<span style=\"{css}\">");
+                }
+                open = css;
+            }
+            escape_into(&mut html, c);
+            continue;
+        }
+        if chars.next_if_eq(&'[').is_none() {
+            // Not a CSI sequence; skip the one character that follows, and
+            // the charset a `ESC (` or the like goes on to designate.
+            if chars.next().is_some_and(|c| "()*+".contains(c)) {
+                chars.next();
+            }
+            continue;
+        }
+        let mut params = String::new();
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                if c == 'm' {
+                    attributes.apply(&params);
+                }
+                break;
+            }
+            params.push(c);
+        }
+    }
+    if !open.is_empty() {
+        html.push_str("</span>");
+    }
+    html
+}
+
+/// Escapes text for HTML, in element content as well as in attributes.
+pub fn escape(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        escape_into(&mut html, c);
+    }
+    html
+}
+
+fn escape_into(html: &mut String, c: char) {
+    match c {
+        '&' => html.push_str("&amp;"),
+        '<' => html.push_str("&lt;"),
+        '>' => html.push_str("&gt;"),
+        '"' => html.push_str("&quot;"),
+        c => html.push(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The style `params` leave behind, starting from no attributes.
+    fn css(params: &str) -> String {
+        let mut attributes = Attributes::default();
+        attributes.apply(params);
+        attributes.css()
+    }
+
+    #[test]
+    fn maps_sgr_codes_to_css() {
+        for (params, expected) in [
+            ("", ""),
+            ("0", ""),
+            ("1", "font-weight:bold;"),
+            ("2", "opacity:0.7;"),
+            ("3", "font-style:italic;"),
+            ("4", "text-decoration:underline;"),
+            ("1;22", ""),
+            ("3;4;23;24", ""),
+            ("31", "color:#cd0000;"),
+            ("94", "color:#5c5cff;"),
+            ("42", "background-color:#00cd00;"),
+            ("107", "background-color:#ffffff;"),
+            ("31;39", ""),
+            ("1;31;0", ""),
+            ("1;91", "font-weight:bold;color:#ff0000;"),
+            ("7", ""),
+        ] {
+            assert_eq!(css(params), expected, "{params}");
+        }
+    }
+
+    #[test]
+    fn maps_256_colors() {
+        for (params, expected) in [
+            ("38;5;1", "color:#cd0000;"),
+            ("38;5;12", "color:#5c5cff;"),
+            ("38;5;16", "color:rgb(0,0,0);"),
+            ("38;5;196", "color:rgb(255,0,0);"),
+            ("38;5;110", "color:rgb(135,175,215);"),
+            ("38;5;231", "color:rgb(255,255,255);"),
+            ("38;5;232", "color:rgb(8,8,8);"),
+            ("38;5;255", "color:rgb(238,238,238);"),
+            ("48;5;21", "background-color:rgb(0,0,255);"),
+            ("38;5", ""),
+        ] {
+            assert_eq!(css(params), expected, "{params}");
+        }
+    }
+
+    #[test]
+    fn maps_truecolor() {
+        assert_eq!(css("38;2;1;2;3"), "color:rgb(1,2,3);");
+        assert_eq!(css("48;2;255;128;0"), "background-color:rgb(255,128,0);");
+        // The codes after a color still apply.
+        assert_eq!(css("38;2;1;2;3;1"), "font-weight:bold;color:rgb(1,2,3);");
+        assert_eq!(css("38;2;1;2"), "");
+    }
+
+    #[test]
+    fn renders_colored_cargo_output() {
+        let text =
+            "\x1b[0m\x1b[1m\x1b[91merror[E0425]\x1b[0m\x1b[0m\x1b[1m: cannot find value `x`\x1b[0m";
+        assert_eq!(
+            to_html(text),
+            "<span style=\"font-weight:bold;color:#ff0000;\">error[E0425]</span>\
+             <span style=\"font-weight:bold;\">: cannot find value `x`</span>"
+        );
+    }
+
+    #[test]
+    fn escapes_html_and_drops_other_sequences() {
+        assert_eq!(
+            to_html("a < b && \"c\" > d"),
+            "a &lt; b &amp;&amp; &quot;c&quot; &gt; d"
+        );
+        assert_eq!(to_html("\x1b[2Kdone\x1b(B"), "done");
+        assert_eq!(
+            to_html("\x1b[32mok\x1b[0m <\x1b[1m\x1b[0m"),
+            "<span style=\"color:#00cd00;\">ok</span> &lt;"
+        );
+    }
+
+    #[test]
+    fn strips_sequences() {
+        assert_eq!(
+            strip("\x1b[1m\x1b[32m   Compiling\x1b[0m step1"),
+            "   Compiling step1"
+        );
+    }
+}
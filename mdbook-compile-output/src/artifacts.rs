@@ -0,0 +1,372 @@
+//! Files a stage run writes, shown as a gallery below its output.
+//!
+//! A directive with `artifacts=<glob>` runs its job with a fresh scratch
+//! directory; `{out}` in the arguments and the `COMPILE_OUTPUT_DIR`
+//! environment variable point there. The files matching the glob are kept
+//! with the output, also in the cache, and copied next to the chapter into
+//! `<chapter>.artifacts/`. mdbook copies them into the rendered book like any
+//! other file in the source directory.
+
+use crate::ansi;
+use crate::render::Style;
+use mdbook::book::Chapter;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use regex::Regex;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Replaced with the scratch directory in the job's arguments.
+pub const PLACEHOLDER: &str = "{out}";
+
+/// The environment variable holding the scratch directory.
+pub const ENV: &str = "COMPILE_OUTPUT_DIR";
+
+/// How the scratch directory appears in captured output. Its real path
+/// differs from run to run.
+pub const SHOWN_AS: &str = "[OUT]";
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+/// Translates a glob into a regular expression over paths relative to the
+/// scratch directory: `*` and `?` stay within one path component, `**/`
+/// matches any number of directories.
+pub fn glob_regex(glob: &str) -> Result<Regex, Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => {
+                if chars.next_if_eq(&'/').is_some() {
+                    pattern.push_str("(?:[^/]*/)*");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).map_err(|e| Error::msg(format!("invalid glob `{glob}`: {e}")))
+}
+
+/// Lists the files below `dir` matching `glob`, relative to `dir`. Numbers
+/// in the names are compared by value, so `_cluster_2.png` comes before
+/// `_cluster_10.png`.
+pub fn collect(dir: &Path, glob: &str) -> Result<Vec<PathBuf>, Error> {
+    let regex = glob_regex(glob)?;
+    let mut files = Vec::new();
+    collect_into(dir, Path::new(""), &regex, &mut files)?;
+    files.sort_by(|a, b| natural_order(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(files)
+}
+
+fn collect_into(
+    root: &Path,
+    rel: &Path,
+    regex: &Regex,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(root.join(rel))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            collect_into(root, &rel, regex, files)?;
+        } else if regex.is_match(&rel.to_string_lossy().replace('\\', "/")) {
+            files.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Compares names with runs of digits taken as numbers.
+fn natural_order(a: &str, b: &str) -> std::cmp::Ordering {
+    let split = |name: &str| -> Vec<(String, u64)> {
+        let mut parts: Vec<(String, u64)> = Vec::new();
+        let mut text = String::new();
+        let mut digits = String::new();
+        for c in name.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+            } else {
+                if !digits.is_empty() {
+                    parts.push((std::mem::take(&mut text), digits.parse().unwrap_or(0)));
+                    digits.clear();
+                }
+                text.push(c);
+            }
+        }
+        parts.push((text, digits.parse().unwrap_or(0)));
+        parts
+    };
+    split(a).cmp(&split(b))
+}
+
+/// Copies `files` from `from` to `to`, leaving files whose contents did not
+/// change untouched, so `mdbook serve` does not see them as edits. Other
+/// files in `to` are removed.
+pub fn copy(from: &Path, files: &[PathBuf], to: &Path) -> Result<(), Error> {
+    let failed = |e: std::io::Error, path: &Path| {
+        Error::new(e).context(format!("failed to copy the artifact {}", path.display()))
+    };
+    for file in files {
+        let (source, target) = (from.join(file), to.join(file));
+        let data = fs::read(&source).map_err(|e| failed(e, &source))?;
+        if fs::read(&target).ok().as_ref() == Some(&data) {
+            continue;
+        }
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir).map_err(|e| failed(e, dir))?;
+        }
+        fs::write(&target, data).map_err(|e| failed(e, &target))?;
+    }
+    if to.is_dir() {
+        remove_others(to, Path::new(""), files)?;
+    }
+    Ok(())
+}
+
+fn remove_others(root: &Path, rel: &Path, keep: &[PathBuf]) -> Result<(), Error> {
+    for entry in fs::read_dir(root.join(rel))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            remove_others(root, &rel, keep)?;
+        } else if !keep.contains(&rel) {
+            fs::remove_file(root.join(&rel))?;
+        }
+    }
+    Ok(())
+}
+
+/// The directory, relative to the chapter file, that the artifacts of the
+/// `number`th directive (counting from 1) in a chapter are published to.
+pub fn link_dir(ch: &Chapter, number: usize, stage: &str) -> PathBuf {
+    let source = ch
+        .source_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(&ch.name));
+    let mut dir = source.file_stem().unwrap_or_default().to_os_string();
+    dir.push(".artifacts");
+    let stage = stage.replace(['/', '\\', '.'], "_");
+    PathBuf::from(dir).join(format!("{number:02}-{stage}"))
+}
+
+/// Where [`link_dir`] is on disk.
+pub fn path(ctx: &PreprocessorContext, ch: &Chapter, link_dir: &Path) -> PathBuf {
+    let chapter_dir = ch
+        .source_path
+        .as_deref()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(""));
+    ctx.root
+        .join(&ctx.config.book.src)
+        .join(chapter_dir)
+        .join(link_dir)
+}
+
+/// Renders the published artifacts: images as a gallery, any other file as
+/// a link. The HTML renderer gets a wrapping grid of captioned figures,
+/// every other renderer one Markdown image per paragraph.
+pub fn render(files: &[PathBuf], link_dir: &Path, style: &Style) -> String {
+    if files.is_empty() {
+        return "*no artifacts*".to_string();
+    }
+    let link = |file: &Path| url(&link_dir.join(file).to_string_lossy().replace('\\', "/"));
+    let (images, others): (Vec<&PathBuf>, Vec<&PathBuf>) = files.iter().partition(|file| {
+        file.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+    });
+
+    let mut result = String::new();
+    if style.html && !images.is_empty() {
+        result.push_str(
+            "<div class=\"compile-output-gallery\" \
+             style=\"display:flex;flex-wrap:wrap;gap:1em\">\n",
+        );
+        for image in &images {
+            // Names come from whatever the stage wrote.
+            let link = ansi::escape(&link(image));
+            let name = ansi::escape(&image.to_string_lossy());
+            let _ = writeln!(
+                result,
+                "<figure style=\"margin:0;max-width:calc(50% - 0.5em)\">\
+                 <a href=\"{link}\"><img src=\"{link}\" alt=\"{name}\" style=\"max-width:100%\"></a>\
+                 <figcaption><code>{name}</code></figcaption></figure>"
+            );
+        }
+        result.push_str("</div>\n\n");
+    } else {
+        for image in &images {
+            let name = label(&image.to_string_lossy());
+            let _ = write!(result, "![{name}](<{}>)\n\n", link(image));
+        }
+    }
+    for other in &others {
+        let name = label(&other.to_string_lossy());
+        let _ = writeln!(result, "- [{name}](<{}>)", link(other));
+    }
+    result.trim_end().to_string()
+}
+
+/// Percent-encodes a relative path for use as a link target, so a `#`, `?`,
+/// `%` or space in a file name stays part of the path.
+fn url(path: &str) -> String {
+    let mut url = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                url.push(byte as char)
+            }
+            byte => {
+                let _ = write!(url, "%{byte:02X}");
+            }
+        }
+    }
+    url
+}
+
+/// Escapes the characters that would end or change a Markdown link text.
+fn label(name: &str) -> String {
+    let mut label = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '\\' | '[' | ']' | '<' | '>' | '*' | '_' | '`') {
+            label.push('\\');
+        }
+        label.push(c);
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ansi::AnsiMode;
+    use crate::render::{Format, Streams};
+    use std::cmp::Ordering;
+
+    fn style(html: bool) -> Style<'static> {
+        Style {
+            stage: "step5",
+            language: "text",
+            format: Format::Log,
+            streams: Streams::Auto,
+            footer: false,
+            ansi: AnsiMode::Strip,
+            html,
+            diagnostics: false,
+            collapse: false,
+            timings: false,
+            toolchain: false,
+        }
+    }
+
+    #[test]
+    fn globs_match_within_path_components() {
+        let regex = glob_regex("*.png").unwrap();
+        assert!(regex.is_match("clusters.tsv_cluster_1.png"));
+        assert!(!regex.is_match("plots/clusters.png"));
+        assert!(!regex.is_match("clusters.png.txt"));
+
+        let regex = glob_regex("plot?.svg").unwrap();
+        assert!(regex.is_match("plot1.svg"));
+        assert!(!regex.is_match("plot10.svg"));
+        assert!(!regex.is_match("plot/.svg"));
+    }
+
+    #[test]
+    fn double_stars_match_directories() {
+        let regex = glob_regex("**/*.png").unwrap();
+        assert!(regex.is_match("a.png"));
+        assert!(regex.is_match("plots/2024/a.png"));
+        assert!(!regex.is_match("a.svg"));
+
+        let regex = glob_regex("plots/**").unwrap();
+        assert!(regex.is_match("plots/a/b.tsv"));
+        assert!(!regex.is_match("other/a.tsv"));
+    }
+
+    #[test]
+    fn globs_take_other_characters_literally() {
+        let regex = glob_regex("out(1)+[a].tsv").unwrap();
+        assert!(regex.is_match("out(1)+[a].tsv"));
+        assert!(!regex.is_match("out1a.tsv"));
+        assert!(!glob_regex("a.tsv").unwrap().is_match("a_tsv"));
+    }
+
+    #[test]
+    fn orders_numbers_by_value() {
+        let mut names = [
+            "cluster_10.png",
+            "cluster_2.png",
+            "cluster_1.png",
+            "cluster.png",
+            "a_3/x.png",
+            "a_20/x.png",
+        ];
+        names.sort_by(|a, b| natural_order(a, b));
+        assert_eq!(
+            names,
+            [
+                "a_3/x.png",
+                "a_20/x.png",
+                "cluster.png",
+                "cluster_1.png",
+                "cluster_2.png",
+                "cluster_10.png"
+            ]
+        );
+        assert_eq!(natural_order("run_007", "run_7"), Ordering::Equal);
+        assert_eq!(natural_order("v2", "v2.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn escapes_names_in_html() {
+        let files = [PathBuf::from("a&b <\"x\">.png")];
+        let rendered = render(&files, Path::new("ch.artifacts/01-step5"), &style(true));
+        assert!(rendered.contains(
+            "src=\"ch.artifacts/01-step5/a%26b%20%3C%22x%22%3E.png\" \
+             alt=\"a&amp;b &lt;&quot;x&quot;&gt;.png\""
+        ));
+        assert!(rendered.contains("<code>a&amp;b &lt;&quot;x&quot;&gt;.png</code>"));
+        assert!(!rendered.contains("<\"x\">"));
+    }
+
+    #[test]
+    fn renders_markdown_for_other_renderers() {
+        let files = [PathBuf::from("plot_1.png"), PathBuf::from("clusters.tsv")];
+        assert_eq!(
+            render(&files, Path::new("ch.artifacts/01-step5"), &style(false)),
+            "![plot\\_1.png](<ch.artifacts/01-step5/plot_1.png>)\n\n\
+             - [clusters.tsv](<ch.artifacts/01-step5/clusters.tsv>)"
+        );
+        assert_eq!(render(&[], Path::new(""), &style(true)), "*no artifacts*");
+    }
+
+    #[test]
+    fn encodes_link_targets() {
+        let files = [PathBuf::from("run #1?a=100% (ü).png")];
+        let encoded = "ch.artifacts/01-step5/run%20%231%3Fa%3D100%25%20%28%C3%BC%29.png";
+        let rendered = render(&files, Path::new("ch.artifacts/01-step5"), &style(true));
+        assert!(rendered.contains(&format!("<a href=\"{encoded}\"><img src=\"{encoded}\"")));
+        assert!(rendered.contains("alt=\"run #1?a=100% (ü).png\""));
+        assert_eq!(
+            render(&files, Path::new("ch.artifacts/01-step5"), &style(false)),
+            format!("![run #1?a=100% (ü).png](<{encoded}>)")
+        );
+    }
+
+    #[test]
+    fn escapes_brackets_in_markdown_labels() {
+        let files = [PathBuf::from("fit[a]`b`.png"), PathBuf::from("x](y).tsv")];
+        assert_eq!(
+            render(&files, Path::new("out"), &style(false)),
+            "![fit\\[a\\]\\`b\\`.png](<out/fit%5Ba%5D%60b%60.png>)\n\n\
+             - [x\\](y).tsv](<out/x%5D%28y%29.tsv>)"
+        );
+    }
+}
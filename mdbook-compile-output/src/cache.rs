@@ -0,0 +1,169 @@
+//! A content-addressed cache of stage outputs.
+//!
+//! The key of an entry is a hash over everything that can change what cargo
+//! prints for a stage: its location in the book, its sources,
+//! `Cargo.toml`/`Cargo.lock`, the cargo arguments and the toolchain version.
+//! Unchanged stages are therefore never rebuilt, no matter how often
+//! `mdbook serve` reloads the book. Files a job collected as artifacts are
+//! kept in a `<key>.artifacts` directory next to its entry.
+
+use crate::artifacts;
+use crate::stage::{self, Job, StageOutput};
+use mdbook::errors::Error;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Bumped whenever the layout of [`StageOutput`] changes, so entries written
+/// by an older preprocessor are not mistaken for current ones.
+const FORMAT_VERSION: &str = "4";
+
+/// Held while an entry is written.
+static PUTTING: Mutex<()> = Mutex::new(());
+
+/// How the preprocessor uses the cache, set with the `cache` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheMode {
+    /// Reuse cached outputs and store new ones.
+    #[default]
+    On,
+    /// Neither read nor write the cache; every stage is run again.
+    Off,
+    /// Delete all cached outputs, then behave like `on`.
+    Clear,
+}
+
+pub struct Cache {
+    /// `None` when the cache is switched off.
+    dir: Option<PathBuf>,
+    /// The book root, which stage paths in keys are relative to.
+    root: PathBuf,
+    /// Whether cached outputs are used, or only new ones stored.
+    read: bool,
+}
+
+impl Cache {
+    pub fn open(dir: PathBuf, root: &Path, mode: CacheMode) -> Result<Cache, Error> {
+        // Stage paths are canonical, so the root has to be as well.
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        if mode == CacheMode::Off {
+            return Ok(Cache {
+                dir: None,
+                root,
+                read: false,
+            });
+        }
+        if mode == CacheMode::Clear && dir.exists() {
+            fs::remove_dir_all(&dir).map_err(|e| {
+                Error::new(e).context(format!("failed to clear the cache in {}", dir.display()))
+            })?;
+        }
+        Ok(Cache {
+            dir: Some(dir),
+            root,
+            read: true,
+        })
+    }
+
+    /// Makes every stage run again, while still storing what it printed.
+    pub fn write_only(self) -> Cache {
+        Cache {
+            read: false,
+            ..self
+        }
+    }
+
+    /// Computes the key for a job. Returns `None` when the cache is
+    /// switched off.
+    pub fn key(&self, job: &Job) -> Result<Option<String>, Error> {
+        if self.dir.is_none() {
+            return Ok(None);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(FORMAT_VERSION);
+        hasher.update([0]);
+        // Stages copied forward unchanged still print their own paths.
+        let location = job.path.strip_prefix(&self.root).unwrap_or(&job.path);
+        hasher.update(location.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        for arg in &job.args {
+            hasher.update(arg.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update([job.color as u8]);
+        if let Some(glob) = &job.artifacts {
+            hasher.update(glob.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(stage::rustc(job, "-vV")?.as_bytes());
+        hash_dir(&mut hasher, &job.path, Path::new(""))?;
+        let digest = hasher.finalize();
+        Ok(Some(digest.iter().map(|b| format!("{b:02x}")).collect()))
+    }
+
+    /// Whether the cache holds an output for the job.
+    pub fn has(&self, job: &Job) -> Result<bool, Error> {
+        Ok(match (&self.dir, self.key(job)?) {
+            (Some(dir), Some(key)) if self.read => dir.join(format!("{key}.json")).is_file(),
+            _ => false,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<StageOutput> {
+        let dir = self.dir.as_ref().filter(|_| self.read)?;
+        let data = fs::read(dir.join(format!("{key}.json"))).ok()?;
+        // A corrupt entry is just a miss; it is overwritten after the rerun.
+        let mut output: StageOutput = serde_json::from_slice(&data).ok()?;
+        output.artifact_dir = dir.join(format!("{key}.artifacts"));
+        Some(output)
+    }
+
+    pub fn put(&self, key: &str, output: &StageOutput) -> Result<(), Error> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir).map_err(|e| {
+            Error::new(e).context(format!("failed to create the cache in {}", dir.display()))
+        })?;
+        // Jobs differing only in options outside the key, like `timeout`,
+        // share an entry and may finish at the same time.
+        let _putting = PUTTING.lock().unwrap_or_else(|e| e.into_inner());
+        if !output.artifacts.is_empty() {
+            let artifact_dir = dir.join(format!("{key}.artifacts"));
+            artifacts::copy(&output.artifact_dir, &output.artifacts, &artifact_dir)?;
+        }
+        // Write to a temporary file first so a reader never sees half an entry.
+        // Named after the process, so another build writing the same entry
+        // does not rename it away.
+        let tmp = dir.join(format!("{key}.json.{}.tmp", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(output)?)?;
+        fs::rename(&tmp, dir.join(format!("{key}.json")))?;
+        Ok(())
+    }
+}
+
+/// Feeds the relative path and contents of every file below `root/rel` into
+/// the hasher, in a stable order. Build output and hidden entries are skipped.
+fn hash_dir(hasher: &mut Sha256, root: &Path, rel: &Path) -> Result<(), Error> {
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if name == "target" || name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let rel = rel.join(&name);
+        if entry.file_type()?.is_dir() {
+            hash_dir(hasher, root, &rel)?;
+        } else {
+            hasher.update(rel.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(fs::read(root.join(&rel))?);
+            hasher.update([0]);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+//! Checks that the Rust snippets of a chapter still match its stages.
+//!
+//! Every ```` ```rust ```` block of a chapter is compared with the sources of
+//! the stages the chapter's directives refer to. A snippet that parses as
+//! Rust matches when each of its items appears in one of the stage files;
+//! any other snippet has to appear as a whole. Whitespace is ignored, so
+//! reformatting a stage does not make the book out of date, but any change
+//! to the code or its comments does. A block preceded by the [`OPT_OUT`]
+//! marker is not checked.
+
+use crate::items;
+use mdbook::errors::Error;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Put on the line before a snippet that is not taken from a stage.
+pub const OPT_OUT: &str = "<!-- compile-output: no-check -->";
+
+/// What the preprocessor does with snippets, set with the `check-snippets`
+/// key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnippetCheck {
+    /// Do not check them.
+    #[default]
+    Off,
+    /// Print out of date snippets and go on.
+    Warn,
+    /// Fail the build if any snippet is out of date.
+    Fail,
+}
+
+/// A fenced `rust` block of a chapter.
+struct Snippet {
+    /// The line number of the opening fence, counting from 1.
+    line: usize,
+    code: String,
+}
+
+/// Returns one message for every snippet in `content` that matches none of
+/// the `stages`. `location` turns a line number into the chapter position
+/// the message starts with.
+pub fn snippets(
+    content: &str,
+    stages: &[PathBuf],
+    location: impl Fn(usize) -> String,
+) -> Result<Vec<String>, Error> {
+    let snippets = find_snippets(content);
+    if snippets.is_empty() || stages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sources = Vec::new();
+    for stage in stages {
+        collect_sources(stage, &mut sources)?;
+    }
+    let names = stages
+        .iter()
+        .filter_map(|stage| stage.file_name())
+        .map(|name| name.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut problems = Vec::new();
+    for snippet in snippets {
+        let parts = match items::split(&snippet.code) {
+            Some(parts) if !parts.is_empty() => parts,
+            _ => vec![snippet.code.clone()],
+        };
+        let missing = parts.iter().find(|part| {
+            let part = squeeze(part);
+            !sources.iter().any(|source| source.contains(part.as_str()))
+        });
+        if let Some(missing) = missing {
+            // The first line names the item well enough to find it.
+            let line = missing.lines().map(str::trim).find(|line| !line.is_empty());
+            let line = line.unwrap_or_default();
+            let mut start: String = line.chars().take(60).collect();
+            if start.len() < line.len() {
+                start.push_str("...");
+            }
+            problems.push(format!(
+                "{}: rust snippet is out of date, no match in {names} for `{start}`",
+                location(snippet.line)
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Finds the `rust` blocks, leaving out those after an [`OPT_OUT`] marker.
+fn find_snippets(content: &str) -> Vec<Snippet> {
+    let mut snippets = Vec::new();
+    let mut previous = "";
+    let mut lines = content.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let Some((fence, info)) = opening_fence(line) else {
+            if !line.trim().is_empty() {
+                previous = line.trim();
+            }
+            continue;
+        };
+        let mut code = String::new();
+        for (_, line) in lines.by_ref() {
+            let trimmed = line.trim();
+            if trimmed.starts_with(fence) && trimmed.chars().all(|c| fence.starts_with(c)) {
+                break;
+            }
+            // mdbook hides lines starting with `# ` but still compiles them.
+            let hidden = line.trim_start();
+            let line = match hidden.strip_prefix("# ") {
+                Some(rest) => rest,
+                None if hidden == "#" => "",
+                None => line,
+            };
+            code.push_str(line);
+            code.push('\n');
+        }
+        let language = info.split([',', ' ']).next().unwrap_or("");
+        if language == "rust" && previous != OPT_OUT && !code.trim().is_empty() {
+            snippets.push(Snippet {
+                line: index + 1,
+                code,
+            });
+        }
+        previous = "";
+    }
+    snippets
+}
+
+/// Splits a line opening a fenced block into its fence and info string.
+fn opening_fence(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    let marker = trimmed.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let length = trimmed.chars().take_while(|&c| c == marker).count();
+    (length >= 3).then(|| (&trimmed[..length], trimmed[length..].trim()))
+}
+
+/// Reads every `.rs` file of a stage, without whitespace.
+fn collect_sources(dir: &Path, sources: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)
+        .map_err(|e| Error::new(e).context(format!("failed to read {}", dir.display())))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "target" || name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_sources(&path, sources)?;
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            sources.push(squeeze(&fs::read_to_string(&path)?));
+        }
+    }
+    Ok(())
+}
+
+/// Removes all whitespace.
+fn squeeze(text: &str) -> String {
+    text.chars().filter(|c| !c.is_whitespace()).collect()
+}
@@ -0,0 +1,196 @@
+//! Per-book settings read from the `[preprocessor.compile-output]` table
+//! in `book.toml`.
+
+use crate::ansi::AnsiMode;
+use crate::cache::CacheMode;
+use crate::check::SnippetCheck;
+use crate::directive;
+use crate::normalize::{Normalizer, Replace, RuleSet};
+use crate::render::{Format, Streams};
+use crate::snapshot::SnapshotMode;
+use crate::stage::Expect;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The name of our table below `[preprocessor]` in `book.toml`.
+pub const TABLE: &str = "compile-output";
+
+/// Keys mdbook itself reads from every preprocessor table.
+/// They are not ours to validate.
+const MDBOOK_KEYS: &[&str] = &[
+    "command",
+    "renderer",
+    "renderers",
+    "before",
+    "after",
+    "optional",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory holding one cargo project per stage.
+    pub stage_root: PathBuf,
+    /// Arguments passed to `cargo` for every stage, starting with the
+    /// subcommand a directive's `cmd=` option replaces.
+    pub cargo_args: Vec<String>,
+    /// Language of the fenced code block the output is wrapped in.
+    pub language: String,
+    /// Whether stage outputs are cached between builds.
+    pub cache: CacheMode,
+    /// Where cached outputs are stored, relative to the book root.
+    ///
+    /// This is deliberately not inside the build dir itself: mdbook's
+    /// renderers empty their output directory before writing to it.
+    pub cache_dir: PathBuf,
+    /// Where `run-all` writes a log per stage run, relative to the book root.
+    pub log_dir: PathBuf,
+    /// A glob over the directory names in `stage_root` that `run-all` runs
+    /// in addition to the stages the chapters use.
+    pub stages: Option<String>,
+    /// Where jobs collecting artifacts get their scratch directories,
+    /// relative to the book root.
+    pub scratch_dir: PathBuf,
+    /// The cargo target directory all stages share, relative to the book
+    /// root, so their common dependencies are built once. Like the cache it
+    /// stays out of the build dir, which the renderers empty. An empty path
+    /// leaves every stage with a `target` directory of its own.
+    pub target_dir: PathBuf,
+    /// Whether the dependencies of the stages are built before any stage
+    /// runs, so the outputs only show the stages themselves being built.
+    /// Without it, a dependency shows up as compiled in whichever stage
+    /// happens to build it first.
+    pub warm: bool,
+    /// The rustup toolchain the stages run with, unless a directive names
+    /// another. Unset, rustup picks it the way it does in the stage
+    /// directory.
+    pub toolchain: Option<String>,
+    /// Whether cargo runs with `--offline --locked`, after checking that
+    /// every crate the stages lock is in the local cargo cache.
+    pub offline: bool,
+    /// How many stages are built at the same time; `0` means one per CPU.
+    pub jobs: usize,
+    /// Seconds a stage may run before it is killed; `0` means no limit.
+    /// Directives can override it with `timeout=N`.
+    pub timeout: u64,
+    /// What a timed out stage does to the build.
+    pub on_timeout: OnTimeout,
+    /// The outcome expected from directives without an `expect=` option.
+    pub expect: Expect,
+    /// How the output as a whole is presented.
+    pub format: Format,
+    /// Which of cargo's output streams are rendered.
+    pub streams: Streams,
+    /// Whether a line with the exit code and wall time follows the output.
+    pub footer: bool,
+    /// What happens to ANSI escape sequences in the output.
+    pub ansi: AnsiMode,
+    /// Whether cargo reports diagnostics as JSON, rendered one block each.
+    pub diagnostics: bool,
+    /// Whether the error codes in the output are explained below it, with
+    /// the text of `rustc --explain`.
+    pub explain: bool,
+    /// Whether the HTML renderer folds each output under a one-line summary.
+    pub collapse: bool,
+    /// Whether rendered output is recorded to, replayed from or verified
+    /// against snapshot files next to the chapters.
+    pub snapshots: SnapshotMode,
+    /// Whether the `rust` blocks of a chapter are checked against the
+    /// stages its directives refer to.
+    pub check_snippets: SnippetCheck,
+    /// The built-in normalization rules applied to captured output.
+    pub normalize: Vec<RuleSet>,
+    /// User rules applied after the built-in ones.
+    pub replace: Vec<Replace>,
+    /// `normalize` and `replace`, compiled.
+    #[serde(skip)]
+    pub normalizer: Normalizer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnTimeout {
+    /// Stop the build with an error.
+    #[default]
+    Fail,
+    /// Render a block saying the stage timed out and carry on.
+    Continue,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            stage_root: PathBuf::from("rust_stages"),
+            cargo_args: vec!["test".to_string(), "--release".to_string()],
+            language: "text".to_string(),
+            cache: CacheMode::default(),
+            cache_dir: PathBuf::from(".compile-output/cache"),
+            scratch_dir: PathBuf::from(".compile-output/scratch"),
+            log_dir: PathBuf::from(".compile-output/logs"),
+            target_dir: PathBuf::from(".compile-output/target"),
+            warm: true,
+            offline: false,
+            toolchain: None,
+            stages: None,
+            jobs: 0,
+            timeout: 0,
+            on_timeout: OnTimeout::default(),
+            expect: Expect::default(),
+            format: Format::default(),
+            streams: Streams::default(),
+            footer: false,
+            ansi: AnsiMode::default(),
+            diagnostics: false,
+            explain: false,
+            collapse: false,
+            snapshots: SnapshotMode::default(),
+            check_snippets: SnippetCheck::default(),
+            normalize: vec![RuleSet::Cargo, RuleSet::Libtest],
+            replace: Vec::new(),
+            normalizer: Normalizer::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The number of worker threads, with `0` resolved to the CPU count.
+    pub fn jobs(&self) -> usize {
+        match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        }
+    }
+
+    /// The shared target directory below `root`, if stages share one.
+    pub fn target_dir(&self, root: &Path) -> Option<PathBuf> {
+        (!self.target_dir.as_os_str().is_empty()).then(|| root.join(&self.target_dir))
+    }
+
+    /// Reads the `[preprocessor.compile-output]` table, falling back to the
+    /// defaults for every key that is not set.
+    pub fn from_context(ctx: &PreprocessorContext) -> Result<Config, Error> {
+        let mut table = match ctx.config.get_preprocessor(TABLE) {
+            Some(table) => table.clone(),
+            None => toml::value::Table::new(),
+        };
+        for key in MDBOOK_KEYS {
+            table.remove(*key);
+        }
+        let mut config: Config = toml::Value::Table(table).try_into().map_err(|e| {
+            Error::msg(format!(
+                "invalid [preprocessor.{TABLE}] table in book.toml: {e}"
+            ))
+        })?;
+        let target_dir = config.target_dir(&ctx.root);
+        if let Some(toolchain) = &config.toolchain {
+            config.toolchain = Some(directive::parse_toolchain(toolchain).map_err(|e| {
+                e.context(format!("invalid [preprocessor.{TABLE}] table in book.toml"))
+            })?);
+        }
+        config.normalizer = Normalizer::new(&config.normalize, &config.replace, target_dir)
+            .map_err(|e| e.context(format!("invalid [preprocessor.{TABLE}] table in book.toml")))?;
+        Ok(config)
+    }
+}
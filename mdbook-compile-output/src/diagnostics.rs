@@ -0,0 +1,336 @@
+//! Compiler diagnostics from cargo's `--message-format=json-diagnostic-rendered-ansi`.
+//!
+//! With that format cargo prints one JSON object per line on stdout, mixed
+//! with whatever the test binaries print there. The `compiler-message`
+//! objects are turned into one block per diagnostic; all other lines are
+//! left for the normal rendering of the streams.
+
+use crate::render::{self, Style};
+use serde::Deserialize;
+use std::fmt::Write;
+
+/// The flag that makes cargo report diagnostics as JSON.
+pub const MESSAGE_FORMAT: &str = "--message-format=json-diagnostic-rendered-ansi";
+
+#[derive(Debug, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Diagnostic {
+    message: String,
+    code: Option<Code>,
+    level: String,
+    spans: Vec<Span>,
+    children: Vec<Diagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Code {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Span {
+    file_name: String,
+    line_start: usize,
+    is_primary: bool,
+    suggested_replacement: Option<String>,
+}
+
+/// Whether a line of stdout is one of cargo's JSON messages.
+fn is_message(line: &str) -> bool {
+    line.starts_with("{\"reason\":")
+}
+
+/// Collects the compiler diagnostics from cargo's stdout. Diagnostics
+/// without a source location, like "aborting due to 2 previous errors",
+/// and repeats from building the same file for several targets are skipped.
+pub fn parse(stdout: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for line in stdout.lines().filter(|line| is_message(line)) {
+        let Ok(message) = serde_json::from_str::<CargoMessage>(line) else {
+            continue;
+        };
+        let Some(diagnostic) = message.message else {
+            continue;
+        };
+        if message.reason != "compiler-message"
+            || diagnostic.primary_span().is_none()
+            || diagnostics
+                .iter()
+                .any(|seen| seen.rendered == diagnostic.rendered)
+        {
+            continue;
+        }
+        diagnostics.push(diagnostic);
+    }
+    diagnostics
+}
+
+/// Removes cargo's JSON messages from captured output.
+pub fn strip_messages(text: &str) -> String {
+    text.split_inclusive('\n')
+        .filter(|line| !is_message(line))
+        .collect()
+}
+
+impl Diagnostic {
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_ref().map(|code| code.code.as_str())
+    }
+
+    fn primary_span(&self) -> Option<&Span> {
+        self.spans.iter().find(|span| span.is_primary)
+    }
+}
+
+/// Renders one block per diagnostic: a heading line with severity, code and
+/// location, rustc's own rendering, and the fixes rustc suggests. With the
+/// HTML renderer each block gets an anchor `<stage>-<code>` chapters can
+/// link to, numbered from the second diagnostic with the same code on.
+pub fn render(diagnostics: &[Diagnostic], stage: &str, style: &Style) -> String {
+    let mut result = String::new();
+    let mut anchors: Vec<String> = Vec::new();
+    for diagnostic in diagnostics {
+        if style.html {
+            let base = format!("{stage}-{}", diagnostic.code().unwrap_or(&diagnostic.level))
+                .to_lowercase();
+            let count = anchors
+                .iter()
+                .filter(|anchor| anchor.as_str() == base)
+                .count();
+            let _ = write!(result, "<a id=\"{base}");
+            if count > 0 {
+                let _ = write!(result, "-{}", count + 1);
+            }
+            result.push_str("\"></a>\n\n");
+            anchors.push(base);
+        }
+
+        let _ = write!(result, "**{}", diagnostic.level);
+        if let Some(code) = diagnostic.code() {
+            let _ = write!(result, "[{code}]");
+        }
+        let _ = write!(result, "**: {}", diagnostic.message);
+        if let Some(span) = diagnostic.primary_span() {
+            let _ = write!(result, " (`{}:{}`)", span.file_name, span.line_start);
+        }
+        result.push_str("\n\n");
+
+        if let Some(rendered) = &diagnostic.rendered {
+            result.push_str(&render::block(style, rendered.trim_end()));
+            result.push_str("\n\n");
+        }
+
+        for child in diagnostic
+            .children
+            .iter()
+            .filter(|child| child.level == "help")
+        {
+            let _ = write!(result, "- *{}:* {}", child.level, child.message);
+            for span in &child.spans {
+                if let Some(replacement) = &span.suggested_replacement {
+                    let _ = write!(result, " — `{}:{}`: ", span.file_name, span.line_start);
+                    if replacement.trim().is_empty() {
+                        result.push_str("remove it");
+                    } else {
+                        let _ = write!(result, "`{}`", replacement.trim());
+                    }
+                }
+            }
+            result.push('\n');
+        }
+        if !result.ends_with("\n\n") {
+            result.push('\n');
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ansi::AnsiMode;
+    use crate::render::{Format, Streams};
+    use serde_json::{Value, json};
+
+    fn style(html: bool) -> Style<'static> {
+        Style {
+            stage: "step1",
+            language: "text",
+            format: Format::Log,
+            streams: Streams::Auto,
+            footer: false,
+            ansi: AnsiMode::Strip,
+            html,
+            diagnostics: true,
+            collapse: false,
+            timings: false,
+            toolchain: false,
+        }
+    }
+
+    fn span(line: usize, primary: bool, replacement: Option<&str>) -> Value {
+        json!({
+            "file_name": "src/lib.rs",
+            "line_start": line,
+            "is_primary": primary,
+            "suggested_replacement": replacement,
+        })
+    }
+
+    /// One line of cargo's JSON output with a compiler message.
+    fn message(
+        level: &str,
+        code: Option<&str>,
+        text: &str,
+        spans: Value,
+        children: Value,
+    ) -> String {
+        // Cargo puts `reason` first, `json!` would sort it after `message`.
+        let message = json!({
+            "message": text,
+            "code": code.map(|code| json!({ "code": code, "explanation": null })),
+            "level": level,
+            "spans": spans,
+            "children": children,
+            "rendered": format!("{level}: {text}\n --> src/lib.rs\n"),
+        });
+        format!(
+            "{{\"reason\":\"compiler-message\",\
+             \"package_id\":\"path+file:///book/rust_stages/step1#0.1.0\",\
+             \"message\":{message}}}"
+        )
+    }
+
+    fn unresolved(name: &str, line: usize) -> String {
+        message(
+            "error",
+            Some("E0425"),
+            &format!("cannot find value `{name}` in this scope"),
+            json!([span(line, true, None)]),
+            json!([]),
+        )
+    }
+
+    #[test]
+    fn skips_other_lines_and_diagnostics_without_a_location() {
+        let stdout = [
+            "running 0 tests".to_string(),
+            unresolved("x", 3),
+            r#"{"reason":"build-finished","success":false}"#.to_string(),
+            message(
+                "error",
+                None,
+                "aborting due to 1 previous error",
+                json!([]),
+                json!([]),
+            ),
+            message(
+                "warning",
+                None,
+                "only a secondary span",
+                json!([span(1, false, None)]),
+                json!([]),
+            ),
+        ]
+        .join("\n");
+        let diagnostics = parse(&stdout);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code(), Some("E0425"));
+        assert_eq!(strip_messages(&format!("{stdout}\n")), "running 0 tests\n");
+    }
+
+    #[test]
+    fn removes_repeats_from_other_targets() {
+        let stdout = [unresolved("x", 3), unresolved("x", 3), unresolved("y", 4)].join("\n");
+        let messages: Vec<_> = parse(&stdout).into_iter().map(|d| d.message).collect();
+        assert_eq!(
+            messages,
+            [
+                "cannot find value `x` in this scope",
+                "cannot find value `y` in this scope"
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_anchors_per_code() {
+        let stdout = [
+            unresolved("x", 3),
+            unresolved("y", 4),
+            message(
+                "warning",
+                None,
+                "unused variable: `z`",
+                json!([span(5, true, None)]),
+                json!([]),
+            ),
+            unresolved("w", 6),
+        ]
+        .join("\n");
+        let diagnostics = parse(&stdout);
+        let rendered = render(&diagnostics, "step1", &style(true));
+        let anchors: Vec<&str> = rendered
+            .lines()
+            .filter_map(|line| line.strip_prefix("<a id=\""))
+            .collect();
+        assert_eq!(
+            anchors,
+            [
+                "step1-e0425\"></a>",
+                "step1-e0425-2\"></a>",
+                "step1-warning\"></a>",
+                "step1-e0425-3\"></a>"
+            ]
+        );
+        assert!(!render(&diagnostics, "step1", &style(false)).contains("<a id="));
+    }
+
+    #[test]
+    fn renders_headings_and_suggestions() {
+        let stdout = message(
+            "warning",
+            None,
+            "unused import: `std::fmt`",
+            json!([span(1, true, None)]),
+            json!([
+                {
+                    "message": "remove the whole `use` item",
+                    "code": null,
+                    "level": "help",
+                    "spans": [span(1, true, Some(""))],
+                    "children": [],
+                    "rendered": null,
+                },
+                {
+                    "message": "`#[warn(unused_imports)]` on by default",
+                    "code": null,
+                    "level": "note",
+                    "spans": [],
+                    "children": [],
+                    "rendered": null,
+                },
+                {
+                    "message": "use the full path",
+                    "code": null,
+                    "level": "help",
+                    "spans": [span(7, true, Some(" std::fmt::Display "))],
+                    "children": [],
+                    "rendered": null,
+                },
+            ]),
+        );
+        assert_eq!(
+            render(&parse(&stdout), "step1", &style(false)),
+            "**warning**: unused import: `std::fmt` (`src/lib.rs:1`)\n\n\
+             ```text\nwarning: unused import: `std::fmt`\n --> src/lib.rs\n```\n\n\
+             - *help:* remove the whole `use` item — `src/lib.rs:1`: remove it\n\
+             - *help:* use the full path — `src/lib.rs:7`: `std::fmt::Display`\n\n"
+        );
+    }
+}
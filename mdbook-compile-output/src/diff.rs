@@ -0,0 +1,123 @@
+//! Unified diffs between two stage directories.
+
+use crate::directive::Options;
+use crate::items;
+use mdbook::errors::Error;
+use similar::TextDiff;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Context lines around each change when the directive sets none.
+const DEFAULT_CONTEXT: usize = 3;
+
+/// A stage as it appears in a diff: its name in the directive and its
+/// directory.
+pub struct Side<'a> {
+    pub name: &'a str,
+    pub path: &'a Path,
+}
+
+/// Diffs the `path` of a `stage_diff` directive between the two stages, or
+/// every file they contain if it names none. With `functions` only those
+/// functions are compared, each on its own. Returns an empty string if
+/// nothing changed.
+pub fn diff(from: &Side, to: &Side, options: &Options) -> Result<String, Error> {
+    let context = options.context.unwrap_or(DEFAULT_CONTEXT);
+    let files = match &options.path {
+        Some(path) => vec![PathBuf::from(path)],
+        None => {
+            let mut files = list_files(from.path)?;
+            for file in list_files(to.path)? {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+            files.sort();
+            files
+        }
+    };
+
+    let mut result = String::new();
+    for file in &files {
+        let old = read(from.path, file, options.path.is_some())?;
+        let new = read(to.path, file, options.path.is_some())?;
+        let (Some(old), Some(new)) = (old, new) else {
+            // Not text; a diff of it would say nothing.
+            continue;
+        };
+        let header = |side: &Side| format!("{}/{}", side.name, file.display());
+        if options.functions.is_empty() {
+            result.push_str(&unified(&old, &new, &header(from), &header(to), context));
+            continue;
+        }
+        for function in &options.functions {
+            let old_text = items::find(&old, function).map_err(|e| e.context(header(from)))?;
+            let new_text = items::find(&new, function).map_err(|e| e.context(header(to)))?;
+            if old_text.is_none() && new_text.is_none() {
+                return Err(Error::msg(format!(
+                    "no item `{function}` in {} or {}",
+                    header(from),
+                    header(to)
+                )));
+            }
+            result.push_str(&unified(
+                &old_text.unwrap_or_default(),
+                &new_text.unwrap_or_default(),
+                &format!("{} {function}", header(from)),
+                &format!("{} {function}", header(to)),
+                context,
+            ));
+        }
+    }
+    Ok(result.trim_end().to_string())
+}
+
+fn unified(old: &str, new: &str, old_header: &str, new_header: &str, context: usize) -> String {
+    let mut old = old.to_string();
+    let mut new = new.to_string();
+    // Otherwise a missing newline at the end shows up as a change.
+    for text in [&mut old, &mut new] {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+    TextDiff::from_lines(&old, &new)
+        .unified_diff()
+        .context_radius(context)
+        .header(old_header, new_header)
+        .to_string()
+}
+
+/// Reads a file of a stage. A missing file reads as empty, unless the
+/// directive named it, and a file that is not UTF-8 as `None`.
+fn read(stage: &Path, file: &Path, named: bool) -> Result<Option<String>, Error> {
+    let path = stage.join(file);
+    if !path.exists() && !named {
+        return Ok(Some(String::new()));
+    }
+    let data = fs::read(&path)
+        .map_err(|e| Error::new(e).context(format!("failed to read {}", path.display())))?;
+    Ok(String::from_utf8(data).ok())
+}
+
+/// The files of a stage relative to its directory, without build output,
+/// hidden entries and `Cargo.lock`.
+fn list_files(stage: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(stage.join(&dir))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == "target" || name == "Cargo.lock" || name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(dir.join(name));
+            } else {
+                files.push(dir.join(name));
+            }
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,569 @@
+//! Parsing of the `{{#compile_output:...}}`, `{{#run_output:...}}`,
+//! `{{#stage_diff:...}}`, `{{#stage_item:...}}` and `{{#rustc_explain:...}}`
+//! directives.
+//!
+//! A directive names a stage, optionally followed by `key=value` options:
+//!
+//! ```text
+//! {{#compile_output:step3 cmd=run release=false test=tes_scale01 expect=fail}}
+//! ```
+//!
+//! `run_output` runs the stage's binary; the arguments for it follow `--`:
+//!
+//! ```text
+//! {{#run_output:step4 timeout=60 -- -f tests/data/data.tsv --clusters 8}}
+//! ```
+//!
+//! `stage_diff` names two stages, separated by `..`, and runs nothing:
+//!
+//! ```text
+//! {{#stage_diff:step2..step3 path=src/lib.rs functions=calc_ek,run}}
+//! ```
+//!
+//! `stage_item` takes a file of the stage and the items to show from it:
+//!
+//! ```text
+//! {{#stage_item:step3 src/lib.rs SimulatedAnnealing::calc_ek cluster_rows}}
+//! ```
+//!
+//! `rustc_explain` takes an error code instead of a stage:
+//!
+//! ```text
+//! {{#rustc_explain:E0425}}
+//! ```
+//!
+//! Words are separated by whitespace. Single or double quotes group text
+//! containing whitespace into one word (`args="--clusters 8"`), and inside
+//! double quotes a backslash escapes the next character.
+
+use crate::ansi::AnsiMode;
+use crate::artifacts;
+use crate::explain;
+use crate::render::{Format, Streams};
+use crate::stage::Expect;
+use mdbook::errors::Error;
+use serde::de::DeserializeOwned;
+
+/// The directives this preprocessor expands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Kind {
+    /// Shows what cargo prints for a stage, `cargo test` by default.
+    #[default]
+    CompileOutput,
+    /// Shows what the stage's binary prints when run by `cargo run`.
+    RunOutput,
+    /// Shows the changes from one stage to another as a unified diff.
+    StageDiff,
+    /// Shows items cut from a source file of a stage.
+    StageItem,
+    /// Shows what `rustc --explain` says about an error code.
+    RustcExplain,
+}
+
+impl Kind {
+    const ALL: [Kind; 5] = [
+        Kind::CompileOutput,
+        Kind::RunOutput,
+        Kind::StageDiff,
+        Kind::StageItem,
+        Kind::RustcExplain,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::CompileOutput => "compile_output",
+            Kind::RunOutput => "run_output",
+            Kind::StageDiff => "stage_diff",
+            Kind::StageItem => "stage_item",
+            Kind::RustcExplain => "rustc_explain",
+        }
+    }
+
+    /// The options a directive of this kind takes.
+    fn options(self) -> &'static [&'static str] {
+        match self {
+            Kind::CompileOutput => &[
+                "cmd",
+                "release",
+                "test",
+                "args",
+                "timeout",
+                "expect",
+                "format",
+                "streams",
+                "footer",
+                "ansi",
+                "diagnostics",
+                "artifacts",
+                "toolchain",
+                "explain",
+                "collapse",
+            ],
+            Kind::RunOutput => &[
+                "release",
+                "timeout",
+                "expect",
+                "format",
+                "streams",
+                "footer",
+                "ansi",
+                "diagnostics",
+                "artifacts",
+                "toolchain",
+                "explain",
+                "collapse",
+            ],
+            Kind::StageDiff => &["path", "functions", "context"],
+            Kind::StageItem => &[],
+            Kind::RustcExplain => &["toolchain"],
+        }
+    }
+
+    /// Whether the directive runs cargo.
+    pub fn runs_cargo(self) -> bool {
+        matches!(self, Kind::CompileOutput | Kind::RunOutput)
+    }
+}
+
+/// The stage and options of one directive.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub kind: Kind,
+    /// The stage, or the error code of a `rustc_explain`.
+    pub stage: String,
+    /// Replaces the cargo subcommand, e.g. `cmd=run`.
+    pub cmd: Option<String>,
+    /// Adds or removes `--release`.
+    pub release: Option<bool>,
+    /// Only runs the tests whose name contains this filter.
+    pub test: Option<String>,
+    /// Extra arguments appended to the cargo command line.
+    pub args: Vec<String>,
+    /// Overrides the configured timeout, in seconds.
+    pub timeout: Option<u64>,
+    /// How cargo has to end for the build to go on.
+    pub expect: Option<Expect>,
+    /// Overrides how the output as a whole is presented.
+    pub format: Option<Format>,
+    /// Overrides which output streams are rendered.
+    pub streams: Option<Streams>,
+    /// Overrides whether the exit code and wall time are shown.
+    pub footer: Option<bool>,
+    /// Overrides what happens to escape sequences in the output.
+    pub ansi: Option<AnsiMode>,
+    /// Overrides whether compiler diagnostics get blocks of their own.
+    pub diagnostics: Option<bool>,
+    /// A glob of files the job writes to its scratch directory, shown below
+    /// the output.
+    pub artifacts: Option<String>,
+    /// Overrides the rustup toolchain cargo runs with.
+    pub toolchain: Option<String>,
+    /// Overrides whether the error codes in the output are explained.
+    pub explain: Option<bool>,
+    /// Overrides whether the output is folded under a summary.
+    pub collapse: Option<bool>,
+    /// The arguments after `--` of a `run_output` directive.
+    pub run_args: Vec<String>,
+    /// The file a `stage_diff` compares or a `stage_item` cuts from,
+    /// relative to the stage directories.
+    pub path: Option<String>,
+    /// The items a `stage_item` shows.
+    pub items: Vec<String>,
+    /// Limits a `stage_diff` to these functions.
+    pub functions: Vec<String>,
+    /// Lines of context around the changes of a `stage_diff`.
+    pub context: Option<usize>,
+}
+
+impl Options {
+    /// The two stages of a `stage_diff`, split at the `..` between them.
+    pub fn stage_range(&self) -> Option<(&str, &str)> {
+        let stage = self.stage.as_str();
+        // Skip the `..` of a relative path like `../stages/step1`.
+        stage
+            .match_indices("..")
+            .map(|(index, _)| index)
+            .find(|&index| {
+                index > 0
+                    && !stage[..index].ends_with(['/', '.'])
+                    && !stage[index + 2..].starts_with('/')
+            })
+            .map(|index| (&stage[..index], &stage[index + 2..]))
+            .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+    }
+}
+
+/// Returns the kind of directive and the text between its prefix and `}}`
+/// if the line is a directive.
+pub fn extract(line: &str) -> Option<(Kind, &str)> {
+    let line = line.trim();
+    Kind::ALL.into_iter().find_map(|kind| {
+        let text = line
+            .strip_prefix("{{#")?
+            .strip_prefix(kind.name())?
+            .strip_prefix(':')?
+            .strip_suffix("}}")?;
+        Some((kind, text.trim()))
+    })
+}
+
+/// Parses the text of a directive, as returned by [`extract`].
+pub fn parse(kind: Kind, text: &str) -> Result<Options, Error> {
+    let mut words = split_words(text)?;
+    let run_args = match words.iter().position(|word| word == "--") {
+        Some(separator) if kind == Kind::RunOutput => {
+            let run_args = words.split_off(separator + 1);
+            words.pop();
+            run_args
+        }
+        _ => Vec::new(),
+    };
+    let mut words = words.into_iter();
+    let mut options = Options {
+        kind,
+        stage: words
+            .next()
+            .ok_or_else(|| Error::msg("the directive names no stage"))?,
+        run_args,
+        ..Options::default()
+    };
+    if kind == Kind::RustcExplain && !explain::is_code(&options.stage) {
+        return Err(Error::msg(format!(
+            "expected an error code like `E0425`, found `{}`",
+            options.stage
+        )));
+    }
+    if kind == Kind::StageDiff && options.stage_range().is_none() {
+        return Err(Error::msg(format!(
+            "expected two stages like `step2..step3`, found `{}`",
+            options.stage
+        )));
+    }
+    let mut seen = Vec::new();
+    for word in words {
+        let Some((key, value)) = word.split_once('=') else {
+            if kind == Kind::StageItem {
+                match options.path {
+                    None => options.path = Some(word),
+                    Some(_) => options.items.push(word),
+                }
+                continue;
+            }
+            return Err(Error::msg(format!("expected `key=value`, found `{word}`")));
+        };
+        if seen.contains(&key.to_string()) {
+            return Err(Error::msg(format!("option `{key}` is given twice")));
+        }
+        seen.push(key.to_string());
+        if kind == Kind::RunOutput && ["cmd", "test", "args"].contains(&key) {
+            return Err(Error::msg(format!(
+                "option `{key}` is not available for run_output; \
+                 arguments for the binary go after `--`"
+            )));
+        }
+        if !kind.options().contains(&key) {
+            return Err(Error::msg(format!(
+                "unknown option `{key}` for {}, expected one of {}",
+                kind.name(),
+                kind.options().join(", ")
+            )));
+        }
+        match key {
+            "cmd" => options.cmd = Some(value.to_string()),
+            "release" => options.release = Some(parse_bool(key, value)?),
+            "test" => options.test = Some(value.to_string()),
+            "args" => options.args = split_words(value)?,
+            "timeout" => {
+                options.timeout = Some(value.parse().map_err(|_| {
+                    Error::msg(format!("invalid timeout `{value}`, expected seconds"))
+                })?)
+            }
+            "expect" => options.expect = Some(parse_enum(key, value)?),
+            "format" => options.format = Some(parse_enum(key, value)?),
+            "streams" => options.streams = Some(parse_enum(key, value)?),
+            "footer" => options.footer = Some(parse_bool(key, value)?),
+            "ansi" => options.ansi = Some(parse_enum(key, value)?),
+            "diagnostics" => options.diagnostics = Some(parse_bool(key, value)?),
+            "artifacts" => {
+                artifacts::glob_regex(value)?;
+                options.artifacts = Some(value.to_string());
+            }
+            "toolchain" => options.toolchain = Some(parse_toolchain(value)?),
+            "explain" => options.explain = Some(parse_bool(key, value)?),
+            "collapse" => options.collapse = Some(parse_bool(key, value)?),
+            "path" => options.path = Some(value.to_string()),
+            "functions" => {
+                options.functions = value
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            }
+            "context" => {
+                options.context = Some(value.parse().map_err(|_| {
+                    Error::msg(format!(
+                        "invalid context `{value}`, expected a number of lines"
+                    ))
+                })?)
+            }
+            _ => unreachable!("checked against Kind::options"),
+        }
+    }
+    if kind == Kind::StageItem && options.items.is_empty() {
+        return Err(Error::msg(
+            "expected a file and the items to show from it, \
+             like `src/lib.rs SimulatedAnnealing::calc_ek`",
+        ));
+    }
+    if !options.functions.is_empty() && options.path.is_none() {
+        return Err(Error::msg(
+            "`functions` needs the `path` of the file holding them",
+        ));
+    }
+    Ok(options)
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(Error::msg(format!(
+            "invalid value `{value}` for `{key}`, expected true or false"
+        ))),
+    }
+}
+
+/// A toolchain name as rustup takes it, with or without the `+` of
+/// `cargo +nightly`.
+pub fn parse_toolchain(value: &str) -> Result<String, Error> {
+    let name = value.strip_prefix('+').unwrap_or(value);
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(Error::msg(format!(
+            "invalid toolchain `{value}`, expected a name like `stable` or `nightly-2024-05-01`"
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// Parses a value the way the same key is read from `book.toml`.
+fn parse_enum<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, Error> {
+    T::deserialize(toml::Value::String(value.to_string()))
+        .map_err(|e| Error::msg(format!("invalid value `{value}` for `{key}`: {e}")))
+}
+
+/// Splits text into words, honouring quotes as described in the module docs.
+fn split_words(text: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some(escaped) => word.push(escaped),
+                            None => return Err(unterminated(c)),
+                        },
+                        Some(other) => word.push(other),
+                        None => return Err(unterminated(c)),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn unterminated(quote: char) -> Error {
+    Error::msg(format!("unterminated {quote} quote"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        split_words(text).unwrap()
+    }
+
+    fn stage_range(stage: &str) -> Option<(String, String)> {
+        let options = Options {
+            stage: stage.to_string(),
+            ..Options::default()
+        };
+        options
+            .stage_range()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+    }
+
+    #[test]
+    fn splits_words_at_whitespace() {
+        assert_eq!(words("  step1\tcmd=run \n "), ["step1", "cmd=run"]);
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(
+            words(r#"step1 args="--clusters 8" test='a b'"#),
+            ["step1", "args=--clusters 8", "test=a b"]
+        );
+        assert_eq!(words(r#""" ''"#), ["", ""]);
+        assert_eq!(words(r#"a"b c"d"#), ["ab cd"]);
+    }
+
+    #[test]
+    fn backslashes_escape_inside_double_quotes_only() {
+        assert_eq!(words(r#""say \"hi\" \\""#), [r#"say "hi" \"#]);
+        assert_eq!(words(r"'a\b'"), [r"a\b"]);
+        assert_eq!(words(r"a\b"), [r"a\b"]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        for text in [r#"args="--clusters 8"#, "test='a", r#""a\"#] {
+            assert!(split_words(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn extracts_directives() {
+        assert_eq!(
+            extract("  {{#compile_output: step1 cmd=run }}  "),
+            Some((Kind::CompileOutput, "step1 cmd=run"))
+        );
+        assert_eq!(
+            extract("{{#rustc_explain:E0425}}"),
+            Some((Kind::RustcExplain, "E0425"))
+        );
+        assert_eq!(extract("{{#include file.rs}}"), None);
+        assert_eq!(extract("see {{#compile_output:step1}}"), None);
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse(
+            Kind::CompileOutput,
+            r#"step3 cmd=run release=false test=tes_scale01 args="--features plot" timeout=60"#,
+        )
+        .unwrap();
+        assert_eq!(options.stage, "step3");
+        assert_eq!(options.cmd.as_deref(), Some("run"));
+        assert_eq!(options.release, Some(false));
+        assert_eq!(options.test.as_deref(), Some("tes_scale01"));
+        assert_eq!(options.args, ["--features", "plot"]);
+        assert_eq!(options.timeout, Some(60));
+        assert_eq!(options.footer, None);
+    }
+
+    #[test]
+    fn run_output_arguments_follow_the_separator() {
+        let options = parse(
+            Kind::RunOutput,
+            "step4 timeout=60 -- -f tests/data/data.tsv --clusters 8 -- x",
+        )
+        .unwrap();
+        assert_eq!(options.timeout, Some(60));
+        assert_eq!(
+            options.run_args,
+            ["-f", "tests/data/data.tsv", "--clusters", "8", "--", "x"]
+        );
+
+        let options = parse(Kind::RunOutput, "step4 --").unwrap();
+        assert!(options.run_args.is_empty());
+        // Only run_output has arguments for the binary.
+        assert!(parse(Kind::CompileOutput, "step4 -- -f x").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        for (kind, text) in [
+            (Kind::CompileOutput, ""),
+            (Kind::CompileOutput, "step1 release"),
+            (Kind::CompileOutput, "step1 release=yes"),
+            (Kind::CompileOutput, "step1 timeout=soon"),
+            (Kind::CompileOutput, "step1 footer=true footer=false"),
+            (Kind::CompileOutput, "step1 colour=true"),
+            (Kind::CompileOutput, "step1 expect=maybe"),
+            (Kind::CompileOutput, "step1 toolchain=+"),
+            (Kind::RunOutput, "step1 args=--release"),
+            (Kind::StageDiff, "step1 path=src/lib.rs"),
+            (Kind::StageDiff, "step1..step2 functions=run"),
+            (Kind::StageItem, "step1 src/lib.rs"),
+            (Kind::RustcExplain, "E425"),
+        ] {
+            assert!(parse(kind, text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn parses_stage_items() {
+        let options = parse(
+            Kind::StageItem,
+            r#"step3 src/lib.rs SimulatedAnnealing::calc_ek "impl Display for SimulatedAnnealing""#,
+        )
+        .unwrap();
+        assert_eq!(options.path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(
+            options.items,
+            [
+                "SimulatedAnnealing::calc_ek",
+                "impl Display for SimulatedAnnealing"
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_stage_diffs() {
+        let options = parse(
+            Kind::StageDiff,
+            "step2..step3 path=src/lib.rs functions=calc_ek,,run context=5",
+        )
+        .unwrap();
+        assert_eq!(options.functions, ["calc_ek", "run"]);
+        assert_eq!(options.context, Some(5));
+    }
+
+    #[test]
+    fn splits_stage_ranges() {
+        let range = |from: &str, to: &str| Some((from.to_string(), to.to_string()));
+        assert_eq!(stage_range("step2..step3"), range("step2", "step3"));
+        assert_eq!(
+            stage_range("../stages/step1..../stages/step2"),
+            range("../stages/step1", "../stages/step2")
+        );
+        assert_eq!(
+            stage_range("stages/step1..stages/step2"),
+            range("stages/step1", "stages/step2")
+        );
+        assert_eq!(stage_range("step1"), None);
+        assert_eq!(stage_range("step1.."), None);
+        assert_eq!(stage_range("..step2"), None);
+        assert_eq!(stage_range("../stages/step1"), None);
+    }
+
+    #[test]
+    fn toolchains_may_start_with_a_plus() {
+        assert_eq!(parse_toolchain("+nightly").unwrap(), "nightly");
+        assert_eq!(parse_toolchain("1.80.0").unwrap(), "1.80.0");
+        assert!(parse_toolchain("").is_err());
+        assert!(parse_toolchain("night ly").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Explanations of compiler error codes, as the local `rustc --explain`
+//! prints them, so they work offline and match the toolchain in use.
+
+use crate::ansi;
+use mdbook::errors::Error;
+use regex::Regex;
+use std::path::Path;
+use std::process::Command;
+use std::sync::LazyLock;
+
+/// An error code the way rustc prints it in a diagnostic, e.g. `[E0425]`.
+static CODE_IN_OUTPUT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[(E\d{4})\]").expect("valid regex"));
+
+/// Whether `code` looks like an error code, e.g. `E0425`.
+pub fn is_code(code: &str) -> bool {
+    code.len() == 5 && code.starts_with('E') && code[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The error codes of the diagnostics in captured output, each once, in the
+/// order they first appear.
+pub fn codes(text: &str) -> Vec<&str> {
+    let mut codes = Vec::new();
+    for captures in CODE_IN_OUTPUT.captures_iter(text) {
+        let code = captures.get(1).map_or("", |m| m.as_str());
+        if !codes.contains(&code) {
+            codes.push(code);
+        }
+    }
+    codes
+}
+
+/// Runs `rustc --explain` in `dir`, with `toolchain` if one is given, and
+/// returns the explanation as Markdown.
+pub fn explain(code: &str, toolchain: Option<&str>, dir: &Path) -> Result<String, Error> {
+    let mut command = Command::new("rustc");
+    command.args(["--explain", code]).current_dir(dir);
+    if let Some(toolchain) = toolchain {
+        command.env("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    let output = command
+        .output()
+        .map_err(|e| Error::new(e).context("failed to run rustc --explain"))?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "rustc --explain {code} failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(untested(String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+/// Marks the examples of an explanation `rust,ignore`. rustc prints them
+/// without a language, which `mdbook test` would run as doc tests, and
+/// most of them fail to compile on purpose.
+fn untested(text: &str) -> String {
+    let mut in_block = false;
+    let mut result = Vec::new();
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            if !in_block && line.trim() == "```" {
+                result.push(line.replace("```", "```rust,ignore"));
+                in_block = true;
+                continue;
+            }
+            in_block = !in_block;
+        }
+        result.push(line.to_string());
+    }
+    result.join("\n")
+}
+
+/// An explanation that stays folded until the reader asks for it. Only the
+/// HTML renderer can fold it; other renderers get it under a bold title.
+pub fn collapsible(code: &str, explanation: &str, html: bool) -> String {
+    if !html {
+        return format!("**rustc --explain {code}**\n\n{explanation}");
+    }
+    // The first sentence of an explanation says what the error is about.
+    let summary = explanation.lines().next().unwrap_or_default();
+    format!(
+        "<details>\n<summary><code>{code}</code>: {}</summary>\n\n{explanation}\n\n</details>",
+        ansi::to_html(summary)
+    )
+}
@@ -0,0 +1,300 @@
+//! Rust items found by parsing stage sources.
+//!
+//! Sources are parsed with `syn`, and the text of an item is cut from the
+//! file by the lines its tokens span, so comments and formatting inside the
+//! item are kept as written. Doc comments and attributes belong to the item.
+//!
+//! Items are named the way they are written in Rust: `scale_01` or
+//! `SimulatedAnnealing` for a function, type, trait, constant or module,
+//! `SimulatedAnnealing::calc_ek` for a member of an impl block or trait,
+//! `impl SimulatedAnnealing` or `impl Display for SimulatedAnnealing` for a
+//! whole impl block. A plain name also matches methods, so `calc_ek` alone
+//! is enough as long as no other item has that name.
+
+use mdbook::errors::Error;
+use proc_macro2::Span;
+use syn::spanned::Spanned;
+use syn::{Ident, ImplItem, Item, ItemImpl, TraitItem, Type};
+
+/// Finds the item `name` in a source file and returns its text, dedented.
+pub fn find(source: &str, name: &str) -> Result<Option<String>, Error> {
+    let file = syn::parse_file(source).map_err(|e| {
+        let start = e.span().start();
+        Error::msg(format!(
+            "failed to parse the source at line {}:{}: {e}",
+            start.line,
+            start.column + 1
+        ))
+    })?;
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    Ok(find_in(&file.items, &name).map(|span| cut(source, span)))
+}
+
+fn find_in(items: &[Item], name: &str) -> Option<Span> {
+    let (owner, member) = match name.rsplit_once("::") {
+        Some((owner, member)) => (Some(owner), member),
+        None => (None, name),
+    };
+    // Exact names first, so `run` finds a free function `run` before a
+    // method of that name.
+    if owner.is_none() {
+        let exact = items.iter().find(|item| {
+            item_ident(item).is_some_and(|ident| ident == name)
+                || matches!(item, Item::Impl(block) if impl_name(block) == name)
+        });
+        if let Some(item) = exact {
+            return Some(item.span());
+        }
+    }
+    items.iter().find_map(|item| match item {
+        Item::Impl(block) if owner.is_none_or(|owner| type_name(&block.self_ty) == owner) => {
+            block.items.iter().find_map(|item| match item {
+                ImplItem::Fn(f) if f.sig.ident == member => Some(f.span()),
+                ImplItem::Const(c) if c.ident == member => Some(c.span()),
+                ImplItem::Type(t) if t.ident == member => Some(t.span()),
+                _ => None,
+            })
+        }
+        Item::Trait(tr) if owner.is_none_or(|owner| tr.ident == owner) => {
+            tr.items.iter().find_map(|item| match item {
+                TraitItem::Fn(f) if f.sig.ident == member => Some(f.span()),
+                _ => None,
+            })
+        }
+        Item::Mod(module) => {
+            let (_, items) = module.content.as_ref()?;
+            match owner {
+                Some(owner) if module.ident == owner => find_in(items, member),
+                _ => find_in(items, name),
+            }
+        }
+        _ => None,
+    })
+}
+
+/// The name an item is declared with, for the kinds that have one.
+fn item_ident(item: &Item) -> Option<&Ident> {
+    match item {
+        Item::Fn(f) => Some(&f.sig.ident),
+        Item::Struct(s) => Some(&s.ident),
+        Item::Enum(e) => Some(&e.ident),
+        Item::Union(u) => Some(&u.ident),
+        Item::Trait(t) => Some(&t.ident),
+        Item::Type(t) => Some(&t.ident),
+        Item::Const(c) => Some(&c.ident),
+        Item::Static(s) => Some(&s.ident),
+        Item::Mod(m) => Some(&m.ident),
+        Item::Macro(m) => m.ident.as_ref(),
+        _ => None,
+    }
+}
+
+/// `impl Type` or `impl Trait for Type`, with the last path segments only.
+fn impl_name(block: &ItemImpl) -> String {
+    match &block.trait_ {
+        Some((_, path, _)) => {
+            let name = path
+                .segments
+                .last()
+                .map(|segment| segment.ident.to_string())
+                .unwrap_or_default();
+            format!("impl {name} for {}", type_name(&block.self_ty))
+        }
+        None => format!("impl {}", type_name(&block.self_ty)),
+    }
+}
+
+/// The last path segment of a type, e.g. `SimulatedAnnealing` for
+/// `crate::SimulatedAnnealing<T>`.
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string())
+            .unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// Splits a snippet into the text of its items, leaving out `use`
+/// declarations. Returns `None` if the snippet does not parse as a file.
+pub fn split(source: &str) -> Option<Vec<String>> {
+    let file = syn::parse_file(source).ok()?;
+    Some(
+        file.items
+            .iter()
+            .filter(|item| !matches!(item, Item::Use(_)))
+            .map(|item| cut(source, item.span()))
+            .collect(),
+    )
+}
+
+/// The whole lines a span covers, with their common indentation removed.
+fn cut(source: &str, span: Span) -> String {
+    let (start, end) = (span.start().line, span.end().line);
+    let lines: Vec<&str> = source
+        .lines()
+        .skip(start.saturating_sub(1))
+        .take(end + 1 - start)
+        .collect();
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or("").trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+use std::fmt;
+
+pub struct SimulatedAnnealing {
+    k: usize,
+}
+
+impl SimulatedAnnealing {
+    /// Runs the annealing.
+    pub fn run(&mut self) -> usize {
+        // Cools down.
+        self.k
+    }
+
+    pub fn calc_ek(&self, i: usize) -> f32 {
+        i as f32
+    }
+}
+
+/// The free function wins over the method.
+#[inline]
+pub fn run() {}
+
+impl fmt::Display for SimulatedAnnealing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, \"{}\", self.k)
+    }
+}
+
+pub trait Energy {
+    fn energy(&self) -> f32;
+}
+
+mod plot {
+    pub mod colors {
+        /// The line color.
+        pub fn line() -> &'static str {
+            \"black\"
+        }
+    }
+
+    impl super::SimulatedAnnealing {
+        pub fn plot(&self) {}
+    }
+}
+";
+
+    #[test]
+    fn finds_items() {
+        let display = "impl fmt::Display for SimulatedAnnealing {\n    \
+                       fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {\n        \
+                       write!(f, \"{}\", self.k)\n    }\n}";
+        let cases = [
+            (
+                "run",
+                "/// The free function wins over the method.\n#[inline]\npub fn run() {}",
+            ),
+            (
+                "calc_ek",
+                "pub fn calc_ek(&self, i: usize) -> f32 {\n    i as f32\n}",
+            ),
+            (
+                "SimulatedAnnealing::run",
+                "/// Runs the annealing.\npub fn run(&mut self) -> usize {\n    // Cools down.\n    self.k\n}",
+            ),
+            ("Energy::energy", "fn energy(&self) -> f32;"),
+            (
+                "Energy",
+                "pub trait Energy {\n    fn energy(&self) -> f32;\n}",
+            ),
+            ("impl Display for SimulatedAnnealing", display),
+            ("impl   Display  for SimulatedAnnealing", display),
+            (
+                "SimulatedAnnealing::fmt",
+                "fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {\n    \
+                 write!(f, \"{}\", self.k)\n}",
+            ),
+            (
+                "line",
+                "/// The line color.\npub fn line() -> &'static str {\n    \"black\"\n}",
+            ),
+            (
+                "colors::line",
+                "/// The line color.\npub fn line() -> &'static str {\n    \"black\"\n}",
+            ),
+            ("SimulatedAnnealing::plot", "pub fn plot(&self) {}"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(
+                find(SOURCE, name).unwrap().as_deref(),
+                Some(expected),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn misses_items_that_are_not_there() {
+        for name in [
+            "missing",
+            "Energy::calc_ek",
+            "SimulatedAnnealing::energy",
+            "impl Energy for SimulatedAnnealing",
+            "plot::run",
+        ] {
+            assert_eq!(find(SOURCE, name).unwrap(), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let error = find("fn a() {}\nfn b( {}\n", "a").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("failed to parse the source at line 2:")
+        );
+    }
+
+    #[test]
+    fn splits_snippets_without_use_declarations() {
+        assert_eq!(
+            split("use std::fmt;\n\n/// A.\nfn a() {}\n\nstruct B;\n"),
+            Some(vec![
+                "/// A.\nfn a() {}".to_string(),
+                "struct B;".to_string()
+            ])
+        );
+        assert_eq!(split("let x = 1;"), None);
+    }
+
+    #[test]
+    fn cuts_whole_lines_and_removes_common_indentation() {
+        let source = "mod m {\n    fn f() {\n\n        1\n    }   \n}\n";
+        let file = syn::parse_file(source).unwrap();
+        let Item::Mod(module) = &file.items[0] else {
+            panic!("not a module");
+        };
+        let (_, items) = module.content.as_ref().unwrap();
+        assert_eq!(cut(source, items[0].span()), "fn f() {\n\n    1\n}");
+    }
+}
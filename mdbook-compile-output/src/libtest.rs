@@ -0,0 +1,378 @@
+//! Test results parsed from `cargo test` output.
+//!
+//! cargo announces every test binary on stderr (`Running unittests ...`,
+//! `Doc-tests ...`), and libtest prints the results of that binary on
+//! stdout, starting with `running N tests`. The streams are captured apart,
+//! so the order between them is lost; the results are read from stdout
+//! alone, and the n-th block of results belongs to the n-th announcement.
+
+use crate::render::{self, Style};
+use std::fmt::Write;
+
+#[derive(Debug, Default)]
+pub struct Binary {
+    /// E.g. `unittests src/lib.rs`, `tests/read.rs` or `Doc-tests step3`.
+    name: String,
+    tests: Vec<Test>,
+    /// The `test result:` line, without that prefix.
+    summary: Option<String>,
+}
+
+#[derive(Debug)]
+struct Test {
+    name: String,
+    /// `ok`, `FAILED` or `ignored`, possibly followed by a reason.
+    result: String,
+    /// What the test printed before it failed.
+    failure: Option<String>,
+}
+
+/// Parses the streams of `cargo test`, without escape codes. Returns an
+/// empty list if no test binary ran, e.g. on a compile error.
+pub fn parse(stdout: &str, stderr: &str) -> Vec<Binary> {
+    let mut names = stderr.lines().filter_map(|line| {
+        let line = line.trim();
+        match line.strip_prefix("Running ") {
+            Some(rest) => Some(rest.rsplit_once(" (").map_or(rest, |(name, _)| name)),
+            None => line.starts_with("Doc-tests ").then_some(line),
+        }
+    });
+    let mut binaries: Vec<Binary> = Vec::new();
+    let mut failure: Option<(String, String)> = None;
+    for line in stdout.lines() {
+        let trimmed = line.trim();
+        // Every binary starts with `running N tests`. A binary without the
+        // libtest harness prints none, and leaves the names out of step.
+        if trimmed.starts_with("running ")
+            && (trimmed.ends_with(" test") || trimmed.ends_with(" tests"))
+        {
+            if let Some(binary) = binaries.last_mut() {
+                finish_failure(binary, failure.take());
+            }
+            let name = names.next().map_or_else(
+                || format!("test binary {}", binaries.len() + 1),
+                str::to_string,
+            );
+            binaries.push(Binary {
+                name,
+                ..Binary::default()
+            });
+            continue;
+        }
+        let Some(binary) = binaries.last_mut() else {
+            continue;
+        };
+
+        // Captured output of failed tests: `---- name stdout ----` up to the
+        // next such header or the `failures:` list that ends the section.
+        if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|rest| rest.strip_suffix(" ----"))
+        {
+            finish_failure(binary, failure.take());
+            let name = name
+                .strip_suffix(" stdout")
+                .or_else(|| name.strip_suffix(" stderr"))
+                .unwrap_or(name);
+            failure = Some((name.to_string(), String::new()));
+            continue;
+        }
+        if let Some((_, text)) = &mut failure {
+            if line == "failures:" {
+                finish_failure(binary, failure.take());
+            } else {
+                text.push_str(line);
+                text.push('\n');
+            }
+            continue;
+        }
+
+        if let Some(summary) = trimmed.strip_prefix("test result: ") {
+            binary.summary = Some(summary.to_string());
+        } else if let Some((name, result)) = trimmed
+            .strip_prefix("test ")
+            .and_then(|rest| rest.rsplit_once(" ... "))
+        {
+            binary.tests.push(Test {
+                name: name.to_string(),
+                result: result.to_string(),
+                failure: None,
+            });
+        }
+    }
+    if let Some(binary) = binaries.last_mut() {
+        finish_failure(binary, failure);
+    }
+    binaries
+}
+
+fn finish_failure(binary: &mut Binary, failure: Option<(String, String)>) {
+    let Some((name, text)) = failure else {
+        return;
+    };
+    if let Some(test) = binary.tests.iter_mut().find(|test| test.name == name) {
+        test.failure = Some(text.trim().to_string());
+    }
+}
+
+/// How many tests passed, failed and were ignored, over all binaries.
+pub fn totals(binaries: &[Binary]) -> (usize, usize, usize) {
+    let (mut passed, mut failed, mut ignored) = (0, 0, 0);
+    for test in binaries.iter().flat_map(|binary| &binary.tests) {
+        if test.result == "ok" {
+            passed += 1;
+        } else if test.result.starts_with("FAILED") {
+            failed += 1;
+        } else {
+            ignored += 1;
+        }
+    }
+    (passed, failed, ignored)
+}
+
+/// Renders one table of tests per binary, the captured output of every
+/// failing test below its table, and the totals over all binaries.
+pub fn render(binaries: &[Binary], style: &Style) -> String {
+    let mut result = String::new();
+    for binary in binaries {
+        let _ = write!(result, "**{}**", binary.name);
+        if let Some(summary) = &binary.summary {
+            let _ = write!(result, " — {summary}");
+        }
+        result.push_str("\n\n");
+        if binary.tests.is_empty() {
+            result.push_str("*no tests*\n\n");
+            continue;
+        }
+
+        result.push_str("| test | result |\n|------|--------|\n");
+        for test in &binary.tests {
+            let mark = if test.result == "ok" {
+                "✔"
+            } else if test.result.starts_with("FAILED") {
+                "✘"
+            } else {
+                "–"
+            };
+            let _ = writeln!(
+                result,
+                "| `{}` | {mark} {} |",
+                test.name.replace('|', "\\|"),
+                test.result.replace('|', "\\|")
+            );
+        }
+        result.push('\n');
+
+        for test in &binary.tests {
+            if let Some(failure) = &test.failure {
+                let _ = write!(
+                    result,
+                    "`{}` failed:\n\n{}\n\n",
+                    test.name,
+                    render::block(style, failure)
+                );
+            }
+        }
+    }
+    let (passed, failed, ignored) = totals(binaries);
+    let _ = write!(
+        result,
+        "**Total: {passed} passed, {failed} failed, {ignored} ignored**"
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cargo test --no-fail-fast` of a crate with two failing unit tests,
+    /// an integration test file and a doc test.
+    const STDOUT: &str = "\n\
+running 3 tests
+test loud ... FAILED
+test nope ... FAILED
+test tests::it_works ... ok
+
+failures:
+
+---- loud stdout ----
+some output
+
+thread 'loud' (11899) panicked at src/lib.rs:22:38:
+boom
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+---- nope stdout ----
+
+thread 'nope' (11900) panicked at src/lib.rs:16:13:
+assertion `left == right` failed
+  left: 1
+ right: 2
+
+
+failures:
+    loud
+    nope
+
+test result: FAILED. 1 passed; 2 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+
+running 2 tests
+test integ ... ok
+test skipped ... ignored
+
+test result: ok. 1 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+
+running 1 test
+test src/lib.rs - documented (line 17) ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+all doctests ran in 0.29s; merged doctests compilation took 0.29s
+";
+
+    const STDERR: &str = "\
+    Finished `test` profile [unoptimized + debuginfo] target(s) in 0.01s
+     Running unittests src/lib.rs (target/debug/deps/failing-55b08702f42bedb7)
+error: test failed, to rerun pass `--lib`
+     Running tests/more.rs (target/debug/deps/more-acd2b26af52ed048)
+   Doc-tests failing
+error: 1 target failed:
+    `--lib`
+";
+
+    fn names(binary: &Binary) -> Vec<(&str, &str)> {
+        binary
+            .tests
+            .iter()
+            .map(|test| (test.name.as_str(), test.result.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn pairs_result_blocks_with_announcements() {
+        let binaries = parse(STDOUT, STDERR);
+        let names: Vec<&str> = binaries.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["unittests src/lib.rs", "tests/more.rs", "Doc-tests failing"]
+        );
+        let summaries: Vec<&str> = binaries
+            .iter()
+            .map(|b| b.summary.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            summaries,
+            [
+                "FAILED. 1 passed; 2 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s",
+                "ok. 1 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s",
+                "ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_test_results() {
+        let binaries = parse(STDOUT, STDERR);
+        assert_eq!(
+            names(&binaries[0]),
+            [
+                ("loud", "FAILED"),
+                ("nope", "FAILED"),
+                ("tests::it_works", "ok")
+            ]
+        );
+        assert_eq!(
+            names(&binaries[1]),
+            [("integ", "ok"), ("skipped", "ignored")]
+        );
+        assert_eq!(
+            names(&binaries[2]),
+            [("src/lib.rs - documented (line 17)", "ok")]
+        );
+        assert_eq!(totals(&binaries), (3, 2, 1));
+    }
+
+    #[test]
+    fn attaches_captured_output_to_the_failing_test() {
+        let binaries = parse(STDOUT, STDERR);
+        let failures: Vec<Option<&str>> = binaries[0]
+            .tests
+            .iter()
+            .map(|test| test.failure.as_deref())
+            .collect();
+        assert_eq!(failures[2], None);
+        assert!(
+            failures[0]
+                .unwrap()
+                .starts_with("some output\n\nthread 'loud'")
+        );
+        assert!(failures[0].unwrap().ends_with(
+            "boom\nnote: run with `RUST_BACKTRACE=1` environment variable to display a backtrace"
+        ));
+        assert!(failures[1].unwrap().starts_with("thread 'nope'"));
+        assert!(
+            failures[1]
+                .unwrap()
+                .ends_with("assertion `left == right` failed\n  left: 1\n right: 2")
+        );
+        assert!(binaries[1].tests.iter().all(|test| test.failure.is_none()));
+    }
+
+    #[test]
+    fn output_without_test_binaries_has_no_results() {
+        let stderr = "\
+   Compiling step1 v0.1.0 (rust_stages/step1)
+error[E0425]: cannot find value `x` in this scope
+error: could not compile `step1` (lib test) due to 1 previous error
+";
+        assert!(parse("", stderr).is_empty());
+    }
+
+    #[test]
+    fn ignores_the_order_between_the_streams() {
+        // The interleaved capture may show both results of the unit tests
+        // after cargo announced the doc tests; the streams alone cannot.
+        let stderr = "     Running unittests src/lib.rs (target/debug/deps/sa-0ab6e4d935b8c571)
+   Doc-tests sa
+";
+        let stdout = "
+running 1 test
+test tests::it_works ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+
+running 0 tests
+
+test result: ok. 0 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+        let binaries = parse(stdout, stderr);
+        assert_eq!(binaries[0].name, "unittests src/lib.rs");
+        assert_eq!(names(&binaries[0]), [("tests::it_works", "ok")]);
+        assert!(
+            binaries[0]
+                .summary
+                .as_deref()
+                .unwrap()
+                .starts_with("ok. 1 passed")
+        );
+        assert_eq!(binaries[1].name, "Doc-tests sa");
+        assert!(binaries[1].tests.is_empty());
+        assert!(
+            binaries[1]
+                .summary
+                .as_deref()
+                .unwrap()
+                .starts_with("ok. 0 passed")
+        );
+    }
+
+    #[test]
+    fn names_binaries_without_an_announcement() {
+        let stdout = "\nrunning 1 test\ntest a ... ok\n\ntest result: ok. 1 passed; 0 failed\n";
+        assert_eq!(parse(stdout, "")[0].name, "test binary 1");
+    }
+}
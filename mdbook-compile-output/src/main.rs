@@ -1,20 +1,60 @@
 //! This is a demonstration of an mdBook preprocessor which parses markdown
 //! and replaces compile placeholders with custom output.
 
+mod ansi;
+mod artifacts;
+mod cache;
+mod check;
+mod config;
+mod diagnostics;
+mod diff;
+mod directive;
+mod explain;
+mod items;
+mod libtest;
+mod normalize;
+mod offline;
+mod pool;
+mod render;
+mod run_all;
+mod shared_target;
+mod snapshot;
+mod stage;
+
+use ansi::AnsiMode;
+use cache::Cache;
+use check::SnippetCheck;
+use config::{Config, OnTimeout};
+use directive::{Kind, Options};
+use mdbook::BookItem;
 use mdbook::book::{Book, Chapter};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext};
-use mdbook::BookItem;
+use render::Style;
+use snapshot::SnapshotMode;
+use stage::{Job, StageOutput};
 use std::io;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn main() {
+    if let Some(code) = shared_target::wrapped_rustc() {
+        std::process::exit(code);
+    }
     let mut args = std::env::args().skip(1);
     match args.next().as_deref() {
         Some("supports") => {
             // This preprocessor supports all renderers.
             return;
         }
+        Some("run-all") => match run_all::run(args) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
         Some(arg) => {
             eprintln!("unknown argument: {arg}");
             std::process::exit(1);
@@ -23,7 +63,7 @@ fn main() {
     }
 
     if let Err(e) = handle_preprocessing() {
-        eprintln!("{e}");
+        eprintln!("{e:#}");
         std::process::exit(1);
     }
 }
@@ -35,75 +75,525 @@ impl Preprocessor for CompileOutputPreprocessor {
         "compile-output-preprocessor"
     }
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        let config = Config::from_context(ctx)?;
+        let mut cache = Cache::open(ctx.root.join(&config.cache_dir), &ctx.root, config.cache)?;
+        // Verifying snapshots means checking what the stages print now.
+        if config.snapshots == SnapshotMode::Verify {
+            cache = cache.write_only();
+        }
+
+        // Find every directive first, so independent stages can be built in
+        // parallel before any chapter is rewritten.
+        let mut chapters = Vec::new();
+        let mut outdated = Vec::new();
+        let mut error = None;
         book.for_each_mut(|item| {
+            if error.is_some() {
+                return;
+            }
             if let BookItem::Chapter(ch) = item {
                 if ch.is_draft_chapter() {
                     return;
                 }
-                // Process the chapter content to replace compile placeholders
-                ch.content = process_compile(&ch.content);
+                let directives = match find_directives(ch, ctx, &config) {
+                    Ok(directives) => directives,
+                    Err(e) => {
+                        error = Some(e);
+                        return;
+                    }
+                };
+                // Without the stages there is nothing to check against.
+                if config.check_snippets != SnippetCheck::Off
+                    && config.snapshots != SnapshotMode::Replay
+                {
+                    match check_chapter(ch, &directives) {
+                        Ok(problems) => outdated.extend(problems),
+                        Err(e) => {
+                            error = Some(e);
+                            return;
+                        }
+                    }
+                }
+                chapters.push(directives);
             }
         });
-        Ok(book)
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if !outdated.is_empty() {
+            if config.check_snippets == SnippetCheck::Fail {
+                return Err(Error::msg(format!(
+                    "rust snippets not matching their stages ({}):\n{}",
+                    outdated.len(),
+                    outdated.join("\n")
+                )));
+            }
+            for problem in &outdated {
+                eprintln!("warning: {problem}");
+            }
+        }
+
+        // A stage used by several directives is only run once.
+        let mut jobs: Vec<&Job> = Vec::new();
+        for job in chapters.iter().flatten().filter_map(|d| d.job.as_ref()) {
+            if !jobs.contains(&job) {
+                jobs.push(job);
+            }
+        }
+        let outputs = if config.snapshots == SnapshotMode::Replay {
+            Vec::new()
+        } else {
+            if config.offline {
+                offline::check(&jobs, &ctx.root)?;
+            }
+            if config.warm {
+                shared_target::warm_up(&jobs, &cache)?;
+            }
+            let scratch = ctx.root.join(&config.scratch_dir);
+            let numbered: Vec<(usize, &Job)> = jobs.iter().copied().enumerate().collect();
+            pool::map(&numbered, config.jobs(), |(number, job)| {
+                execute(job, &scratch.join(number.to_string()), &cache)
+            })
+        };
+
+        let mut chapters = chapters.iter();
+        book.for_each_mut(|item| {
+            if error.is_some() {
+                return;
+            }
+            if let BookItem::Chapter(ch) = item {
+                if ch.is_draft_chapter() {
+                    return;
+                }
+                let directives = chapters
+                    .next()
+                    .expect("chapters are visited in the same order");
+                match splice_outputs(&ch.content, directives, &jobs, &outputs, ctx, &config) {
+                    Ok(content) => ch.content = content,
+                    Err(e) => error = Some(e),
+                }
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(book),
+        }
+    }
+}
+
+/// A directive found in a chapter.
+struct Directive {
+    /// Index of the chapter line holding the directive.
+    line_index: usize,
+    /// Where the directive is, for error messages.
+    location: String,
+    options: Options,
+    /// The stage directories it refers to.
+    stages: Vec<PathBuf>,
+    /// What has to run to produce its output, if it runs cargo.
+    job: Option<Job>,
+    /// Where its rendered output is recorded.
+    snapshot: PathBuf,
+    /// Where its artifacts are published, relative to the chapter file.
+    artifact_link: PathBuf,
+    /// The same directory on disk.
+    artifact_dir: PathBuf,
+}
+
+fn find_directives(
+    ch: &Chapter,
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<Vec<Directive>, Error> {
+    let mut directives = Vec::new();
+    for (index, line) in ch.content.lines().enumerate() {
+        if let Some((kind, text)) = directive::extract(line) {
+            let location = directive_location(ch, index + 1, line);
+            let (options, stages, job) =
+                parse_job(kind, text, ch, ctx, config).map_err(|e| e.context(location.clone()))?;
+            let number = directives.len() + 1;
+            let snapshot = snapshot::path(ctx, ch, number, &options.stage);
+            let artifact_link = artifacts::link_dir(ch, number, &options.stage);
+            directives.push(Directive {
+                line_index: index,
+                location,
+                options,
+                stages,
+                job,
+                snapshot,
+                artifact_dir: artifacts::path(ctx, ch, &artifact_link),
+                artifact_link,
+            });
+        }
     }
+    Ok(directives)
 }
 
-fn process_compile(content: &str) -> String {
+/// Replaces every directive line of a chapter with the output of its stage.
+fn splice_outputs(
+    content: &str,
+    directives: &[Directive],
+    jobs: &[&Job],
+    outputs: &[Result<StageOutput, Error>],
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<String, Error> {
+    if config.snapshots == SnapshotMode::Record && !directives.is_empty() {
+        let keep: Vec<&Path> = directives.iter().map(|d| d.snapshot.as_path()).collect();
+        snapshot::prune(&keep)?;
+    }
+    let mut directives = directives.iter().peekable();
     let mut result = String::with_capacity(content.len());
-    for line in content.lines() {
-        if let Some(step) = extract_step_name(line) {
-            // Call the user-implemented compile function
-            result.push_str(&compile(&step));
-        } else {
-            result.push_str(line);
+    for (index, line) in content.lines().enumerate() {
+        match directives.next_if(|directive| directive.line_index == index) {
+            Some(directive) => {
+                let output = directive_output(directive, jobs, outputs, ctx, config)
+                    .map_err(|e| e.context(directive.location.clone()))?;
+                result.push_str(&output);
+            }
+            None => result.push_str(line),
         }
         result.push('\n');
     }
-    result
+    Ok(result)
 }
 
-fn extract_step_name(line: &str) -> Option<String> {
-    let prefix = "{{#compile_output:";
-    if line.trim_start().starts_with(prefix) {
-        // Strip prefix and suffix
-        let after = line.trim_start().strip_prefix(prefix)?;
-        let step = after.strip_suffix("}}")?.trim();
-        Some(step.to_string())
-    } else {
-        None
+/// The rendered output of one directive, taken from its job's output or
+/// from its snapshot, as the snapshot mode asks for.
+fn directive_output(
+    directive: &Directive,
+    jobs: &[&Job],
+    outputs: &[Result<StageOutput, Error>],
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<String, Error> {
+    if config.snapshots == SnapshotMode::Replay {
+        return snapshot::read(&directive.snapshot);
     }
+    let Some(job) = &directive.job else {
+        return record(directive, render_static(directive, ctx, config)?, config);
+    };
+    let job = jobs
+        .iter()
+        .position(|other| *other == job)
+        .expect("every job is in the list");
+    let rendered = match &outputs[job] {
+        Ok(output) => {
+            if directive.options.artifacts.is_some() {
+                artifacts::copy(
+                    &output.artifact_dir,
+                    &output.artifacts,
+                    &directive.artifact_dir,
+                )?;
+            }
+            render_directive(directive, output, ctx, config)?
+        }
+        Err(e) => return Err(Error::msg(format!("{e:#}"))),
+    };
+    record(directive, rendered, config)
 }
 
-/// User-implemented compile function stub. Replace with desired logic.
-fn compile(step: &str) -> String {
-    let path = format!("rust_stages/{}", step.trim());  // Use the step name
+/// Records the rendered output of a directive to its snapshot, or checks
+/// it against the snapshot, as the snapshot mode asks for.
+fn record(directive: &Directive, rendered: String, config: &Config) -> Result<String, Error> {
+    match config.snapshots {
+        SnapshotMode::Record => snapshot::write(&directive.snapshot, &rendered)?,
+        SnapshotMode::Verify => snapshot::verify(&directive.snapshot, &rendered)?,
+        SnapshotMode::Off | SnapshotMode::Replay => {}
+    }
+    Ok(rendered)
+}
 
-    // Run the `cargo test` command in the given directory
-    let output = Command::new("cargo")
-        .arg("test").arg("--release")
-        .current_dir(path)
-        .output()
-        .expect("Failed to execute cargo test");
+/// Parses a directive, resolves the stages it refers to and works out the
+/// job it asks for, if it runs cargo.
+fn parse_job(
+    kind: Kind,
+    text: &str,
+    ch: &Chapter,
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<(Options, Vec<PathBuf>, Option<Job>), Error> {
+    let options = directive::parse(kind, text)?;
+    let stages = match options.stage_range() {
+        // An error code, not a stage.
+        _ if kind == Kind::RustcExplain => Vec::new(),
+        Some((from, to)) if kind == Kind::StageDiff => vec![
+            resolve_stage(from, ch, ctx, config)?,
+            resolve_stage(to, ch, ctx, config)?,
+        ],
+        _ => vec![resolve_stage(&options.stage, ch, ctx, config)?],
+    };
+    if !kind.runs_cargo() {
+        return Ok((options, stages, None));
+    }
+    let timeout = options.timeout.unwrap_or(config.timeout);
+    let job = Job {
+        path: stages[0].clone(),
+        args: cargo_args(&options, config),
+        timeout: (timeout > 0).then(|| Duration::from_secs(timeout)),
+        color: Style::new(config, &options, &ctx.renderer).ansi == AnsiMode::Html,
+        artifacts: options.artifacts.clone(),
+        target_dir: config.target_dir(&ctx.root),
+        toolchain: options.toolchain.clone().or(config.toolchain.clone()),
+    };
+    Ok((options, stages, Some(job)))
+}
 
-    // Get the standard output and error output as strings
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
+/// The configured cargo arguments, adjusted by the options of a directive.
+fn cargo_args(options: &Options, config: &Config) -> Vec<String> {
+    let mut args = config.cargo_args.clone();
+    let cmd = match options.kind {
+        Kind::RunOutput => Some("run".to_string()),
+        _ => options.cmd.clone(),
+    };
+    if let Some(cmd) = &cmd {
+        match args.first_mut() {
+            Some(subcommand) => *subcommand = cmd.clone(),
+            None => args.push(cmd.clone()),
+        }
+    }
+    if options.kind == Kind::RunOutput {
+        // Keeps cargo's progress out of the program's output; compile
+        // errors are still printed.
+        args.insert(1, "--quiet".to_string());
+    }
+    let is_release = |arg: &String| arg == "--release" || arg == "-r";
+    match options.release {
+        Some(true) if !args.iter().any(is_release) => args.push("--release".to_string()),
+        Some(false) => args.retain(|arg| !is_release(arg)),
+        _ => {}
+    }
+    if config.offline {
+        for arg in offline::ARGS.iter().rev() {
+            if !args.iter().any(|other| other == arg) {
+                args.insert(args.len().min(1), arg.to_string());
+            }
+        }
+    }
+    if options.diagnostics.unwrap_or(config.diagnostics) {
+        // Right after the subcommand, where it cannot end up behind `--`.
+        args.insert(args.len().min(1), diagnostics::MESSAGE_FORMAT.to_string());
+    }
+    if let Some(test) = &options.test {
+        args.push(test.clone());
+    }
+    args.extend(options.args.iter().cloned());
+    if options.kind == Kind::RunOutput {
+        args.push("--".to_string());
+        args.extend(options.run_args.iter().cloned());
+    }
+    args
+}
 
-    // Format the output into a Markdown code block
-    if output.status.success() {
-        // If the test passed, wrap the standard output in a code block
-        format!(
-            "```text\n{}\n```",
-            stdout // Include only the standard output
-        )
+/// Finds the cargo project a directive refers to.
+///
+/// A plain name like `step1` is looked up below the configured stage root,
+/// which itself is relative to the book root. A path starting with `./` or
+/// `../` is relative to the directory of the chapter file instead.
+fn resolve_stage(
+    step: &str,
+    ch: &Chapter,
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<PathBuf, Error> {
+    let step = Path::new(step);
+    let path = if step.starts_with(".") || step.starts_with("..") {
+        let chapter_dir = ch
+            .source_path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        ctx.root
+            .join(&ctx.config.book.src)
+            .join(chapter_dir)
+            .join(step)
     } else {
-        // If the test failed, wrap the error output in a code block
-        format!(
-            "```text\n{}\n```",
-            stderr // Include only the error output
-        )
+        ctx.root.join(&config.stage_root).join(step)
+    };
+    // Replaying snapshots works without the stages.
+    if !path.is_dir() && config.snapshots != SnapshotMode::Replay {
+        return Err(Error::msg(format!(
+            "stage directory {} does not exist",
+            path.display()
+        )));
+    }
+    // Absolute and without `..`, the way cargo prints it in its messages.
+    Ok(path.canonicalize().unwrap_or(path))
+}
+
+/// Names the chapter, its source file and the directive line, so a failing
+/// `{{#compile_output:...}}` can be found in the book.
+fn directive_location(ch: &Chapter, line_number: usize, line: &str) -> String {
+    format!("{}: {}", chapter_position(ch, line_number), line.trim())
+}
+
+fn chapter_position(ch: &Chapter, line_number: usize) -> String {
+    let source = match &ch.source_path {
+        Some(path) => format!("{}:{}", path.display(), line_number),
+        None => format!("line {line_number}"),
+    };
+    format!("chapter \"{}\" ({})", ch.name, source)
+}
+
+/// Checks the `rust` blocks of a chapter against every stage its
+/// directives refer to.
+fn check_chapter(ch: &Chapter, directives: &[Directive]) -> Result<Vec<String>, Error> {
+    let mut stages: Vec<PathBuf> = Vec::new();
+    for stage in directives.iter().flat_map(|d| &d.stages) {
+        if !stages.contains(stage) {
+            stages.push(stage.clone());
+        }
+    }
+    check::snippets(&ch.content, &stages, |line| chapter_position(ch, line))
+}
+
+/// Runs the configured cargo command for a stage, or takes its output from
+/// the cache when nothing that influences it has changed. A job collecting
+/// artifacts runs with `scratch` as its emptied scratch directory.
+fn execute(job: &Job, scratch: &Path, cache: &Cache) -> Result<StageOutput, Error> {
+    let key = cache.key(job)?;
+    if let Some(output) = key.as_deref().and_then(|key| cache.get(key)) {
+        return Ok(output);
+    }
+    let Some(glob) = &job.artifacts else {
+        return finish(stage::run_cargo(job, None)?, key, cache);
+    };
+    if scratch.exists() {
+        std::fs::remove_dir_all(scratch)?;
+    }
+    std::fs::create_dir_all(scratch)
+        .map_err(|e| Error::new(e).context(format!("failed to create {}", scratch.display())))?;
+    let mut output = stage::run_cargo(job, Some(scratch))?;
+    let shown = scratch.to_string_lossy();
+    for text in [
+        &mut output.stdout,
+        &mut output.stderr,
+        &mut output.interleaved,
+    ] {
+        *text = text.replace(&*shown, artifacts::SHOWN_AS);
+    }
+    output.artifacts = artifacts::collect(scratch, glob)?;
+    output.artifact_dir = scratch.to_path_buf();
+    finish(output, key, cache)
+}
+
+/// Stores the output of a run in the cache.
+fn finish(output: StageOutput, key: Option<String>, cache: &Cache) -> Result<StageOutput, Error> {
+    // A timeout says nothing about the stage itself, so it is not kept.
+    if let Some(key) = key.as_deref().filter(|_| !output.timed_out) {
+        cache.put(key, &output)?;
+    }
+    Ok(output)
+}
+
+/// Renders a directive that runs no stage, from the stage sources alone or,
+/// for `rustc_explain`, from rustc.
+fn render_static(
+    directive: &Directive,
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<String, Error> {
+    let options = &directive.options;
+    match options.kind {
+        Kind::StageDiff => {
+            let (from, to) = options.stage_range().expect("checked when parsing");
+            let diff = diff::diff(
+                &diff::Side {
+                    name: from,
+                    path: &directive.stages[0],
+                },
+                &diff::Side {
+                    name: to,
+                    path: &directive.stages[1],
+                },
+                options,
+            )?;
+            if diff.is_empty() {
+                return Ok(format!("*no changes from {from} to {to}*"));
+            }
+            Ok(render::fence("diff", &diff))
+        }
+        Kind::StageItem => {
+            let path = options.path.as_deref().expect("checked when parsing");
+            let file = directive.stages[0].join(path);
+            let source = std::fs::read_to_string(&file)
+                .map_err(|e| Error::new(e).context(format!("failed to read {}", file.display())))?;
+            let shown = format!("{}/{path}", options.stage);
+            let mut items = Vec::new();
+            for name in &options.items {
+                match items::find(&source, name).map_err(|e| e.context(shown.clone()))? {
+                    Some(item) => items.push(item),
+                    None => return Err(Error::msg(format!("no item `{name}` in {shown}"))),
+                }
+            }
+            Ok(render::fence("rust,no_run", &items.join("\n\n")))
+        }
+        Kind::RustcExplain => {
+            let toolchain = options.toolchain.as_ref().or(config.toolchain.as_ref());
+            explain::explain(&options.stage, toolchain.map(String::as_str), &ctx.root)
+        }
+        Kind::CompileOutput | Kind::RunOutput => unreachable!("these run cargo"),
+    }
+}
+
+/// Renders the output of a directive's job, after checking that the job
+/// ended the way the directive expects it to.
+fn render_directive(
+    directive: &Directive,
+    output: &StageOutput,
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<String, Error> {
+    let job = directive.job.as_ref().expect("the directive runs cargo");
+    let style = Style::new(config, &directive.options, &ctx.renderer);
+    let normalize = |text: &str| config.normalizer.apply(text, &job.path, &ctx.root);
+    let output = &StageOutput {
+        stdout: normalize(&output.stdout),
+        stderr: normalize(&output.stderr),
+        interleaved: normalize(&output.interleaved),
+        ..output.clone()
+    };
+    if output.timed_out {
+        let seconds = job.timeout.unwrap_or_default().as_secs();
+        if config.on_timeout == OnTimeout::Fail {
+            return Err(Error::msg(format!(
+                "cargo in {} timed out after {seconds} s",
+                job.path.display()
+            )));
+        }
+        return Ok(format!(
+            "> **⏱ timed out after {seconds} s**\n\n{}",
+            render::render(output, &style)
+        ));
+    }
+    let expect = directive.options.expect.unwrap_or(config.expect);
+    let outcome = output.outcome();
+    if !expect.matches(outcome) {
+        return Err(Error::msg(format!(
+            "expected `cargo {}` in {} to end with {expect}, but it ended with {outcome}:\n{}",
+            job.args.join(" "),
+            job.path.display(),
+            ansi::strip(&output.stderr).trim_end()
+        )));
+    }
+    let mut rendered = render::render(output, &style);
+    if directive.options.artifacts.is_some() {
+        rendered.push_str("\n\n");
+        rendered.push_str(&artifacts::render(
+            &output.artifacts,
+            &directive.artifact_link,
+            &style,
+        ));
+    }
+    if directive.options.explain.unwrap_or(config.explain) {
+        for code in explain::codes(&ansi::strip(&output.interleaved)) {
+            let explanation = explain::explain(code, job.toolchain.as_deref(), &job.path)?;
+            rendered.push_str("\n\n");
+            rendered.push_str(&explain::collapsible(code, &explanation, style.html));
+        }
     }
+    Ok(rendered)
 }
 
 pub fn handle_preprocessing() -> Result<(), Error> {
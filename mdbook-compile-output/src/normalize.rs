@@ -0,0 +1,247 @@
+//! Normalization of nondeterministic content in captured output.
+//!
+//! Timings, absolute paths and build hashes change between builds and
+//! machines. They are rewritten before the output is rendered, so the book
+//! and its snapshots only change when the stages do.
+
+use mdbook::errors::Error;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// A built-in set of rules, listed in the `normalize` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleSet {
+    /// Build timings, hashes in `target/*/deps` file names and file lock notes.
+    Cargo,
+    /// Test timings and thread ids in panic messages.
+    Libtest,
+}
+
+impl RuleSet {
+    fn rules(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            RuleSet::Cargo => &[
+                // The status words are colored with `ansi = "html"`.
+                (
+                    r"(?m)^((?:\x1b\[[0-9;]*m)*\s*Finished(?:\x1b\[[0-9;]*m)* .* in )(?:\d+m )?\d+(?:\.\d+)?m?s$",
+                    "${1}[ELAPSED]",
+                ),
+                (r"(deps/[A-Za-z0-9_-]+?)-[0-9a-f]{16}\b", "${1}-[HASH]"),
+                (
+                    r"(?m)^(?:\x1b\[[0-9;]*m)*\s*Blocking(?:\x1b\[[0-9;]*m)* waiting for file lock on .*\n",
+                    "",
+                ),
+            ],
+            RuleSet::Libtest => &[
+                (r"finished in \d+(?:\.\d+)?s", "finished in [ELAPSED]"),
+                (r"(thread '[^']*') \(\d+\)", "${1}"),
+            ],
+        }
+    }
+}
+
+/// A user rule from the `replace` key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replace {
+    /// A regular expression, in the syntax of the `regex` crate.
+    pub pattern: String,
+    /// The replacement; `$1` or `${name}` refer to capture groups.
+    pub with: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Normalizer {
+    rules: Vec<(Regex, String)>,
+    /// The target directory the stages share, if they share one.
+    target_dir: Option<PathBuf>,
+}
+
+impl Normalizer {
+    pub fn new(
+        sets: &[RuleSet],
+        replace: &[Replace],
+        target_dir: Option<PathBuf>,
+    ) -> Result<Normalizer, Error> {
+        let builtin = sets
+            .iter()
+            .flat_map(|set| set.rules())
+            .map(|(pattern, with)| (*pattern, *with));
+        let user = replace
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.with.as_str()));
+        let rules = builtin
+            .chain(user)
+            .map(|(pattern, with)| {
+                Regex::new(pattern)
+                    .map(|regex| (regex, with.to_string()))
+                    .map_err(|e| Error::msg(format!("invalid pattern `{pattern}`: {e}")))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Normalizer { rules, target_dir })
+    }
+
+    /// Rewrites the output of a stage at `stage`. The shared target
+    /// directory becomes `target`, as if the stage had its own, the stage
+    /// directory becomes relative to the book root and the home directory
+    /// `~`, before the rules run in order: built-in sets first, user rules
+    /// after them.
+    pub fn apply(&self, text: &str, stage: &Path, root: &Path) -> String {
+        let mut text = text.to_string();
+        if let Some(target_dir) = &self.target_dir {
+            text = text.replace(&*target_dir.to_string_lossy(), "target");
+        }
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        if let Ok(relative) = stage.strip_prefix(&root) {
+            text = text.replace(&*stage.to_string_lossy(), &relative.to_string_lossy());
+        }
+        if let Some(home) = std::env::var_os("HOME").filter(|home| home.len() > 1) {
+            text = text.replace(&*home.to_string_lossy(), "~");
+        }
+        for (regex, with) in &self.rules {
+            text = regex.replace_all(&text, with.as_str()).into_owned();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(sets: &[RuleSet], text: &str) -> String {
+        Normalizer::new(sets, &[], None).unwrap().apply(
+            text,
+            Path::new("/book/rust_stages/step1"),
+            Path::new("/book"),
+        )
+    }
+
+    #[test]
+    fn replaces_build_timings() {
+        for (text, expected) in [
+            (
+                "    Finished `release` profile [optimized] target(s) in 12.34s",
+                "    Finished `release` profile [optimized] target(s) in [ELAPSED]",
+            ),
+            (
+                "    Finished `test` profile [unoptimized + debuginfo] target(s) in 980ms",
+                "    Finished `test` profile [unoptimized + debuginfo] target(s) in [ELAPSED]",
+            ),
+            (
+                "    Finished dev [unoptimized] target(s) in 1m 02s",
+                "    Finished dev [unoptimized] target(s) in [ELAPSED]",
+            ),
+            (
+                "Finished reading in 3s, said the program",
+                "Finished reading in 3s, said the program",
+            ),
+        ] {
+            assert_eq!(normalize(&[RuleSet::Cargo], text), expected);
+        }
+    }
+
+    #[test]
+    fn replaces_hashes_in_deps_file_names() {
+        assert_eq!(
+            normalize(
+                &[RuleSet::Cargo],
+                "     Running unittests src/lib.rs (target/release/deps/step1-55b08702f42bedb7)\n\
+                 Running target/debug/deps/sim_anneal-0123456789abcdef.exe"
+            ),
+            "     Running unittests src/lib.rs (target/release/deps/step1-[HASH])\n\
+             Running target/debug/deps/sim_anneal-[HASH].exe"
+        );
+        // Only 16 hex digits make a hash.
+        assert_eq!(
+            normalize(&[RuleSet::Cargo], "deps/step1-0123456789"),
+            "deps/step1-0123456789"
+        );
+    }
+
+    #[test]
+    fn drops_file_lock_notes() {
+        assert_eq!(
+            normalize(
+                &[RuleSet::Cargo],
+                "    Blocking waiting for file lock on build directory\n   Compiling step1 v0.1.0\n"
+            ),
+            "   Compiling step1 v0.1.0\n"
+        );
+    }
+
+    #[test]
+    fn replaces_test_timings_and_thread_ids() {
+        assert_eq!(
+            normalize(
+                &[RuleSet::Libtest],
+                "thread 'tests::scale' (5526) panicked at src/lib.rs:22:38:\n\
+                 test result: ok. 1 passed; 0 failed; finished in 0.02s"
+            ),
+            "thread 'tests::scale' panicked at src/lib.rs:22:38:\n\
+             test result: ok. 1 passed; 0 failed; finished in [ELAPSED]"
+        );
+        assert_eq!(
+            normalize(&[RuleSet::Libtest], "thread 'main' panicked"),
+            "thread 'main' panicked"
+        );
+    }
+
+    #[test]
+    fn handles_colored_status_words() {
+        assert_eq!(
+            normalize(
+                &[RuleSet::Cargo],
+                "\x1b[1m\x1b[36m    Blocking\x1b[0m waiting for file lock on build directory\n\
+                 \x1b[1m\x1b[92m    Finished\x1b[0m `test` profile [unoptimized + debuginfo] target(s) in 0.16s\n\
+                 \x1b[1m\x1b[92m     Running\x1b[0m unittests src/lib.rs (target/debug/deps/oc-b4b646b890f007c0)"
+            ),
+            "\x1b[1m\x1b[92m    Finished\x1b[0m `test` profile [unoptimized + debuginfo] target(s) in [ELAPSED]\n\
+             \x1b[1m\x1b[92m     Running\x1b[0m unittests src/lib.rs (target/debug/deps/oc-[HASH])"
+        );
+    }
+
+    #[test]
+    fn rule_sets_apply_only_when_listed() {
+        let text = "    Finished `dev` profile target(s) in 0.5s\nfinished in 0.02s";
+        assert_eq!(normalize(&[], text), text);
+        assert_eq!(
+            normalize(&[RuleSet::Libtest], text),
+            "    Finished `dev` profile target(s) in 0.5s\nfinished in [ELAPSED]"
+        );
+    }
+
+    #[test]
+    fn rewrites_paths_before_user_rules() {
+        let replace = [Replace {
+            pattern: r"rust_stages/(step\d)".to_string(),
+            with: "${1}".to_string(),
+        }];
+        let normalizer = Normalizer::new(
+            &[RuleSet::Cargo],
+            &replace,
+            Some(PathBuf::from("/book/.compile-output/target")),
+        )
+        .unwrap();
+        assert_eq!(
+            normalizer.apply(
+                "   Compiling step1 v0.1.0 (/book/rust_stages/step1)\n\
+                 /book/.compile-output/target/release/deps/step1-55b08702f42bedb7",
+                Path::new("/book/rust_stages/step1"),
+                Path::new("/book"),
+            ),
+            "   Compiling step1 v0.1.0 (step1)\ntarget/release/deps/step1-[HASH]"
+        );
+    }
+
+    #[test]
+    fn invalid_user_patterns_are_errors() {
+        let replace = [Replace {
+            pattern: "(unclosed".to_string(),
+            with: String::new(),
+        }];
+        assert!(Normalizer::new(&[], &replace, None).is_err());
+    }
+}
@@ -0,0 +1,581 @@
+//! Running the stages without network access.
+//!
+//! With `offline` set, cargo gets `--offline --locked`, so it neither
+//! contacts a registry nor touches a lockfile. Before any stage runs, the
+//! lockfiles are checked against the local cargo caches, so a missing crate
+//! fails the build with one list of everything that has to be fetched,
+//! instead of a registry error in the middle of a chapter.
+//!
+//! A lockfile also locks crates only used on other platforms, which cargo
+//! never downloads for this one. The check therefore follows the locked
+//! dependencies from the stage, and skips those the manifest of a package
+//! declares for other targets only.
+
+use crate::stage::Job;
+use mdbook::errors::Error;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The arguments every cargo invocation gets with `offline` set.
+pub const ARGS: [&str; 2] = ["--offline", "--locked"];
+
+/// Checks that the lockfile of every stage the jobs run in exists and that
+/// each crate the stage needs from it is in the local cache.
+pub fn check(jobs: &[&Job], root: &Path) -> Result<(), Error> {
+    let mut stages: Vec<&Path> = Vec::new();
+    for job in jobs {
+        if !stages.contains(&job.path.as_path()) {
+            stages.push(&job.path);
+        }
+    }
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let shown = |stage: &Path| {
+        stage
+            .strip_prefix(&root)
+            .unwrap_or(stage)
+            .display()
+            .to_string()
+    };
+
+    let cache = LocalCache::read();
+    let host = Host::detect()?;
+    let mut problems = Vec::new();
+    // Each missing crate with the stages needing it.
+    let mut missing: Vec<(String, Vec<String>)> = Vec::new();
+    for stage in stages {
+        let path = stage.join("Cargo.lock");
+        let Ok(text) = fs::read_to_string(&path) else {
+            problems.push(format!("{} has no Cargo.lock", shown(stage)));
+            continue;
+        };
+        let lock: toml::Value = text
+            .parse()
+            .map_err(|e| Error::new(e).context(format!("failed to parse {}", path.display())))?;
+        for id in missing_crates(stage, &lock, &cache, &host) {
+            match missing.iter_mut().find(|(known, _)| *known == id) {
+                Some((_, stages)) => stages.push(shown(stage)),
+                None => missing.push((id, vec![shown(stage)])),
+            }
+        }
+    }
+    missing.sort();
+    for (id, stages) in missing {
+        problems.push(format!("{id}, needed by {}", stages.join(", ")));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "offline is set, but not every stage can be built from the local cargo cache ({}):\n{}\n\
+         run `cargo fetch` in these stages on a machine with network access",
+        problems.len(),
+        problems.join("\n")
+    )))
+}
+
+/// A `[[package]]` of a lockfile.
+struct Locked<'a> {
+    name: &'a str,
+    version: &'a str,
+    /// Empty for the packages of the stage itself.
+    source: &'a str,
+    /// `name`, `name version` or `name version (source)`.
+    dependencies: Vec<&'a str>,
+}
+
+/// Follows the locked dependencies from the stage's own packages and
+/// returns `name version` of every crate needed on this host that is not in
+/// the cache.
+fn missing_crates(
+    stage: &Path,
+    lock: &toml::Value,
+    cache: &LocalCache,
+    host: &Host,
+) -> Vec<String> {
+    fn field<'a>(package: &'a toml::Value, key: &str) -> &'a str {
+        package.get(key).and_then(|v| v.as_str()).unwrap_or("")
+    }
+    let packages: Vec<Locked> = lock
+        .get("package")
+        .and_then(|p| p.as_array())
+        .into_iter()
+        .flatten()
+        .map(|package| Locked {
+            name: field(package, "name"),
+            version: field(package, "version"),
+            source: field(package, "source"),
+            dependencies: package
+                .get("dependencies")
+                .and_then(|d| d.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|d| d.as_str())
+                .collect(),
+        })
+        .collect();
+    let find = |entry: &str| {
+        let mut parts = entry.split(' ');
+        let (name, version) = (parts.next().unwrap_or(""), parts.next());
+        packages
+            .iter()
+            .position(|p| p.name == name && version.is_none_or(|v| p.version == v))
+    };
+
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue: Vec<usize> = (0..packages.len())
+        .filter(|&i| packages[i].source.is_empty())
+        .collect();
+    while let Some(index) = queue.pop() {
+        if !seen.insert(index) {
+            continue;
+        }
+        let package = &packages[index];
+        let manifest = if package.source.is_empty() {
+            read_manifest(&stage.join("Cargo.toml"))
+                .filter(|m| m["package"]["name"].as_str() == Some(package.name))
+        } else if cache.has(package.name, package.version, package.source) {
+            cache.manifest(package.name, package.version)
+        } else {
+            missing.push(format!("{} {}", package.name, package.version));
+            continue;
+        };
+        // Dev-dependencies are only built for the stage itself.
+        let dev = package.source.is_empty();
+        for entry in &package.dependencies {
+            let Some(dependency) = find(entry) else {
+                continue;
+            };
+            let needed = manifest
+                .as_ref()
+                .is_none_or(|m| needed_on(m, packages[dependency].name, dev, host));
+            if needed {
+                queue.push(dependency);
+            }
+        }
+    }
+    missing
+}
+
+/// Whether a manifest declares the package `name` as a dependency for this
+/// host. One it does not declare at all is taken as needed.
+fn needed_on(manifest: &toml::Value, name: &str, dev: bool, host: &Host) -> bool {
+    let kinds: &[&str] = if dev {
+        &["dependencies", "build-dependencies", "dev-dependencies"]
+    } else {
+        &["dependencies", "build-dependencies"]
+    };
+    let declares = |table: &toml::Value| {
+        kinds.iter().any(|kind| {
+            let dependencies = table.get(kind).and_then(|d| d.as_table());
+            dependencies.into_iter().flatten().any(|(key, spec)| {
+                spec.get("package").and_then(|p| p.as_str()).unwrap_or(key) == name
+            })
+        })
+    };
+    if declares(manifest) {
+        return true;
+    }
+    let targets = manifest.get("target").and_then(|t| t.as_table());
+    let mut declared = false;
+    for (spec, table) in targets.into_iter().flatten() {
+        if declares(table) {
+            if host.matches(spec) {
+                return true;
+            }
+            declared = true;
+        }
+    }
+    !declared
+}
+
+fn read_manifest(path: &Path) -> Option<toml::Value> {
+    fs::read_to_string(path).ok()?.parse().ok()
+}
+
+/// The platform cargo builds for: its target triple and `cfg` values.
+struct Host {
+    triple: String,
+    /// `name` or `name="value"`, as `rustc --print cfg` prints them.
+    cfg: Vec<String>,
+}
+
+impl Host {
+    fn detect() -> Result<Host, Error> {
+        let rustc = |args: &[&str]| {
+            Command::new("rustc")
+                .args(args)
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                .map_err(|e| {
+                    Error::new(e).context(format!("failed to run rustc {}", args.join(" ")))
+                })
+        };
+        let version = rustc(&["-vV"])?;
+        let triple = version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .unwrap_or_default()
+            .to_string();
+        let cfg = rustc(&["--print", "cfg"])?
+            .lines()
+            .map(str::to_string)
+            .collect();
+        Ok(Host { triple, cfg })
+    }
+
+    /// Whether a `[target.<spec>]` table applies: a target triple or a
+    /// `cfg(...)` expression.
+    fn matches(&self, spec: &str) -> bool {
+        match spec.strip_prefix("cfg(").and_then(|s| s.strip_suffix(')')) {
+            Some(expression) => Cfg {
+                rest: expression,
+                host: self,
+            }
+            .predicate(),
+            None => spec == self.triple,
+        }
+    }
+}
+
+/// Evaluates a `cfg` predicate: `name`, `name = "value"`, `all(..)`,
+/// `any(..)` or `not(..)`.
+struct Cfg<'a> {
+    rest: &'a str,
+    host: &'a Host,
+}
+
+impl<'a> Cfg<'a> {
+    fn predicate(&mut self) -> bool {
+        let name = self.take(|c| c.is_alphanumeric() || c == '_');
+        if self.eat('(') {
+            let mut values = Vec::new();
+            while !self.eat(')') {
+                let before = self.rest.len();
+                values.push(self.predicate());
+                self.eat(',');
+                if self.rest.len() == before {
+                    // Not a predicate; give up on the whole expression.
+                    return false;
+                }
+            }
+            return match name {
+                "all" => values.iter().all(|&v| v),
+                "any" => values.iter().any(|&v| v),
+                "not" => values.len() == 1 && !values[0],
+                _ => false,
+            };
+        }
+        if self.eat('=') {
+            self.eat('"');
+            let value = self.take(|c| c != '"');
+            self.eat('"');
+            return self.host.cfg.contains(&format!("{name}=\"{value}\""));
+        }
+        self.host.cfg.iter().any(|cfg| cfg == name)
+    }
+
+    fn take(&mut self, keep: impl Fn(char) -> bool) -> &'a str {
+        self.rest = self.rest.trim_start();
+        let end = self.rest.find(|c| !keep(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// What the local cargo home holds.
+struct LocalCache {
+    /// `<name>-<version>` of every downloaded registry crate.
+    crates: HashSet<String>,
+    /// The unpacked sources of registry crates, by `<name>-<version>`.
+    unpacked: HashMap<String, PathBuf>,
+    /// The directories of git checkouts, one per repository and commit.
+    git_checkouts: Vec<PathBuf>,
+}
+
+impl LocalCache {
+    fn read() -> LocalCache {
+        let home = cargo_home();
+        let mut crates = HashSet::new();
+        // Downloaded crates are kept as `.crate` files and unpacked into
+        // `src`; either one is enough for cargo.
+        for dir in subdirs(&home.join("registry").join("cache")) {
+            for file in list(&dir) {
+                if let Some(id) = file.to_string_lossy().strip_suffix(".crate") {
+                    crates.insert(id.to_string());
+                }
+            }
+        }
+        let mut unpacked = HashMap::new();
+        for dir in subdirs(&home.join("registry").join("src")) {
+            for path in subdirs(&dir) {
+                let id = path.file_name().unwrap_or_default().to_string_lossy();
+                crates.insert(id.to_string());
+                unpacked.insert(id.into_owned(), path);
+            }
+        }
+        let git_checkouts = subdirs(&home.join("git").join("checkouts"))
+            .iter()
+            .flat_map(|repo| subdirs(repo))
+            .collect();
+        LocalCache {
+            crates,
+            unpacked,
+            git_checkouts,
+        }
+    }
+
+    fn has(&self, name: &str, version: &str, source: &str) -> bool {
+        if source.starts_with("git+") {
+            // Checkouts are named after the first 7 digits of the commit.
+            let commit = source.rsplit_once('#').map_or("", |(_, commit)| commit);
+            let short = commit.get(..7).unwrap_or(commit);
+            return self
+                .git_checkouts
+                .iter()
+                .any(|dir| dir.file_name().is_some_and(|name| name == short));
+        }
+        self.crates.contains(&format!("{name}-{version}"))
+    }
+
+    /// The manifest of a registry crate, if its sources are unpacked.
+    fn manifest(&self, name: &str, version: &str) -> Option<toml::Value> {
+        let dir = self.unpacked.get(&format!("{name}-{version}"))?;
+        read_manifest(&dir.join("Cargo.toml"))
+    }
+}
+
+/// `CARGO_HOME`, or `.cargo` in the home directory.
+fn cargo_home() -> PathBuf {
+    if let Some(home) = std::env::var_os("CARGO_HOME") {
+        return PathBuf::from(home);
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    PathBuf::from(home.unwrap_or_default()).join(".cargo")
+}
+
+/// The names of the entries of a directory; none if it cannot be read.
+fn list(dir: &Path) -> Vec<OsString> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .collect()
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    list(dir)
+        .into_iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linux() -> Host {
+        Host {
+            triple: "x86_64-unknown-linux-gnu".to_string(),
+            cfg: [
+                "debug_assertions",
+                "target_arch=\"x86_64\"",
+                "target_family=\"unix\"",
+                "target_os=\"linux\"",
+                "target_pointer_width=\"64\"",
+                "unix",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+
+    fn toml(text: &str) -> toml::Value {
+        text.parse().unwrap()
+    }
+
+    /// An empty directory of its own for a test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "compile-output-offline-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn matches_targets() {
+        let host = linux();
+        for (spec, expected) in [
+            ("x86_64-unknown-linux-gnu", true),
+            ("x86_64-pc-windows-msvc", false),
+            ("cfg(unix)", true),
+            ("cfg(windows)", false),
+            ("cfg(target_os = \"linux\")", true),
+            ("cfg(target_os=\"macos\")", false),
+            ("cfg(all(unix, not(target_os = \"macos\")))", true),
+            ("cfg(all(unix, not(target_os = \"linux\")))", false),
+            ("cfg(any(windows, target_pointer_width = \"64\"))", true),
+            ("cfg(any(windows, target_arch = \"wasm32\"))", false),
+            ("cfg(all(unix,))", true),
+            ("cfg(all())", true),
+            ("cfg(any())", false),
+            ("cfg(not(unix, windows))", false),
+            ("cfg(unix", false),
+            ("cfg(&)", false),
+        ] {
+            assert_eq!(host.matches(spec), expected, "{spec}");
+        }
+    }
+
+    #[test]
+    fn finds_dependencies_for_the_host() {
+        let manifest = toml(
+            r#"
+            [dependencies]
+            regex = "1"
+            json = { package = "serde_json", version = "1" }
+
+            [dev-dependencies]
+            criterion = "0.5"
+
+            [build-dependencies]
+            cc = "1"
+
+            [target.'cfg(windows)'.dependencies]
+            winapi = "0.3"
+
+            [target.'cfg(unix)'.dependencies]
+            libc = "0.2"
+
+            [target.x86_64-apple-darwin.dependencies]
+            core-foundation = "0.9"
+            "#,
+        );
+        let host = linux();
+        for (name, dev, expected) in [
+            ("regex", false, true),
+            ("serde_json", false, true),
+            ("cc", false, true),
+            ("criterion", true, true),
+            ("winapi", false, false),
+            ("libc", false, true),
+            ("core-foundation", false, false),
+            // Not declared at all, e.g. through a feature of another crate.
+            ("memchr", false, true),
+        ] {
+            assert_eq!(
+                needed_on(&manifest, name, dev, &host),
+                expected,
+                "{name}, dev {dev}"
+            );
+        }
+    }
+
+    #[test]
+    fn lists_the_crates_missing_from_the_cache() {
+        let dir = scratch("missing");
+        let stage = dir.join("stage");
+        fs::create_dir_all(&stage).unwrap();
+        fs::write(
+            stage.join("Cargo.toml"),
+            r#"
+            [package]
+            name = "stage"
+            version = "0.1.0"
+
+            [dependencies]
+            regex = "1"
+            remote = { git = "https://example.com/remote.git" }
+
+            [target.'cfg(windows)'.dependencies]
+            winapi = "0.3"
+            "#,
+        )
+        .unwrap();
+        let regex = dir.join("regex-1.11.1");
+        fs::create_dir_all(&regex).unwrap();
+        fs::write(
+            regex.join("Cargo.toml"),
+            "[dependencies]\nmemchr = \"2\"\n\n[target.'cfg(windows)'.dependencies]\nwinapi = \"0.3\"\n",
+        )
+        .unwrap();
+        let lock = toml(
+            r#"
+            [[package]]
+            name = "stage"
+            version = "0.1.0"
+            dependencies = ["regex", "remote", "winapi"]
+
+            [[package]]
+            name = "regex"
+            version = "1.11.1"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            dependencies = ["memchr 2.7.4", "winapi"]
+
+            [[package]]
+            name = "memchr"
+            version = "2.7.4"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "memchr"
+            version = "1.0.2"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "winapi"
+            version = "0.3.9"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "remote"
+            version = "0.1.0"
+            source = "git+https://example.com/remote.git#0123456789abcdef0123456789abcdef01234567"
+            "#,
+        );
+        let mut cache = LocalCache {
+            crates: HashSet::from(["regex-1.11.1".to_string()]),
+            unpacked: HashMap::from([("regex-1.11.1".to_string(), regex)]),
+            git_checkouts: Vec::new(),
+        };
+        let host = linux();
+        assert_eq!(
+            missing_crates(&stage, &lock, &cache, &host),
+            ["remote 0.1.0", "memchr 2.7.4"]
+        );
+
+        cache.crates.insert("memchr-2.7.4".to_string());
+        cache
+            .git_checkouts
+            .push(dir.join("remote-5f3a9b2c1d4e6f70").join("0123456"));
+        assert!(missing_crates(&stage, &lock, &cache, &host).is_empty());
+
+        // Without its unpacked sources, everything a crate locks is needed.
+        cache.unpacked.clear();
+        assert_eq!(
+            missing_crates(&stage, &lock, &cache, &host),
+            ["winapi 0.3.9"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Turning stage outputs into Markdown.
+
+use crate::ansi::{self, AnsiMode};
+use crate::config::Config;
+use crate::diagnostics;
+use crate::directive::{Kind, Options};
+use crate::libtest;
+use crate::snapshot::SnapshotMode;
+use crate::stage::StageOutput;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Which of cargo's output streams end up in the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Streams {
+    /// stdout if cargo succeeded, stderr if it failed; `interleaved` for
+    /// `run_output`, whose programs often report on stderr.
+    #[default]
+    Auto,
+    Stdout,
+    Stderr,
+    /// One labelled block per stream.
+    Both,
+    /// One block with both streams, merged in roughly the order they were
+    /// written.
+    Interleaved,
+}
+
+/// How the output as a whole is presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// The captured streams as they are.
+    #[default]
+    Log,
+    /// A table of test results per test binary. Falls back to `log` when no
+    /// test binary ran.
+    Table,
+}
+
+/// The rendering settings for one directive: its options where given,
+/// the book-wide configuration otherwise.
+pub struct Style<'a> {
+    /// The name of the stage directory.
+    pub stage: &'a str,
+    pub language: &'a str,
+    pub format: Format,
+    pub streams: Streams,
+    pub footer: bool,
+    /// Already resolved against the renderer: `Html` only for `html`.
+    pub ansi: AnsiMode,
+    /// Whether the book is rendered to HTML.
+    pub html: bool,
+    /// Whether compiler diagnostics are rendered as blocks of their own.
+    pub diagnostics: bool,
+    /// Whether the output is folded under a one-line summary. Only for
+    /// `html`, the other renderers cannot fold.
+    pub collapse: bool,
+    /// Whether wall times are shown. They differ on every run, so they are
+    /// left out of anything recorded in or compared with a snapshot.
+    pub timings: bool,
+    /// Whether the book or the directive picks a toolchain. Its version is
+    /// then shown even without the footer.
+    pub toolchain: bool,
+}
+
+impl<'a> Style<'a> {
+    pub fn new(config: &'a Config, options: &'a Options, renderer: &str) -> Style<'a> {
+        let html = renderer == "html";
+        let ansi = match options.ansi.unwrap_or(config.ansi) {
+            AnsiMode::Html if html => AnsiMode::Html,
+            _ => AnsiMode::Strip,
+        };
+        let stage = Path::new(&options.stage)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&options.stage);
+        Style {
+            stage,
+            language: &config.language,
+            format: options.format.unwrap_or(config.format),
+            streams: match options.streams.unwrap_or(config.streams) {
+                Streams::Auto if options.kind == Kind::RunOutput => Streams::Interleaved,
+                streams => streams,
+            },
+            footer: options.footer.unwrap_or(config.footer),
+            ansi,
+            html,
+            diagnostics: options.diagnostics.unwrap_or(config.diagnostics),
+            collapse: html && options.collapse.unwrap_or(config.collapse),
+            timings: config.snapshots == SnapshotMode::Off,
+            toolchain: options.toolchain.is_some() || config.toolchain.is_some(),
+        }
+    }
+}
+
+pub fn render(output: &StageOutput, style: &Style) -> String {
+    let mut result = String::new();
+    let without_messages;
+    let output = if style.diagnostics {
+        let diagnostics = diagnostics::parse(&output.stdout);
+        result.push_str(&diagnostics::render(&diagnostics, style.stage, style));
+        without_messages = StageOutput {
+            stdout: diagnostics::strip_messages(&output.stdout),
+            interleaved: diagnostics::strip_messages(&output.interleaved),
+            ..output.clone()
+        };
+        &without_messages
+    } else {
+        output
+    };
+
+    let binaries = match style.format {
+        Format::Table => libtest::parse(&ansi::strip(&output.stdout), &ansi::strip(&output.stderr)),
+        Format::Log => Vec::new(),
+    };
+    if !binaries.is_empty() {
+        result.push_str(&libtest::render(&binaries, style));
+    } else {
+        result.push_str(&streams(output, style));
+    }
+    if style.collapse {
+        result = format!(
+            "<details>\n<summary>{}</summary>\n\n{result}\n\n</details>",
+            summary(output, style)
+        );
+    }
+    if style.footer {
+        result.push_str("\n\n");
+        result.push_str(&footer(output, style));
+    } else if style.toolchain && !output.toolchain.is_empty() {
+        let _ = write!(result, "\n\n*{}*", output.toolchain);
+    }
+    result
+}
+
+/// The line a collapsed output is folded under: the test totals if a test
+/// binary ran, how cargo exited otherwise, and the wall time if shown.
+fn summary(output: &StageOutput, style: &Style) -> String {
+    let mark = match output.success {
+        _ if output.timed_out => "⏱",
+        true => "✔",
+        false => "✘",
+    };
+    let binaries = libtest::parse(&ansi::strip(&output.stdout), &ansi::strip(&output.stderr));
+    let outcome = if binaries.is_empty() || output.timed_out {
+        status(output)
+    } else {
+        let (passed, failed, _) = libtest::totals(&binaries);
+        format!("{passed} passed, {failed} failed")
+    };
+    let mut summary = format!("{mark} {outcome}");
+    if style.timings {
+        let _ = write!(summary, " in {:.2} s", output.duration.as_secs_f64());
+    }
+    summary
+}
+
+/// How cargo exited.
+fn status(output: &StageOutput) -> String {
+    match output.exit_code {
+        _ if output.timed_out => "killed on timeout".to_string(),
+        Some(code) => format!("exit code {code}"),
+        None => "killed by a signal".to_string(),
+    }
+}
+
+/// Renders the streams the style selects.
+fn streams(output: &StageOutput, style: &Style) -> String {
+    match style.streams {
+        Streams::Auto if output.success => block(style, &output.stdout),
+        Streams::Auto => block(style, &output.stderr),
+        Streams::Stdout => block(style, &output.stdout),
+        Streams::Stderr => block(style, &output.stderr),
+        Streams::Interleaved => block(style, &output.interleaved),
+        Streams::Both => format!(
+            "**stdout**\n\n{}\n\n**stderr**\n\n{}",
+            block(style, &output.stdout),
+            block(style, &output.stderr)
+        ),
+    }
+}
+
+/// Renders captured text as a code block, dealing with escape sequences
+/// the way the style asks for.
+pub fn block(style: &Style, text: &str) -> String {
+    match style.ansi {
+        AnsiMode::Strip => fence(style.language, &ansi::strip(text)),
+        // Raw HTML, as a fence would escape the spans. `nohighlight` keeps
+        // highlight.js from replacing them with its own markup.
+        AnsiMode::Html => format!(
+            "<pre><code class=\"hljs nohighlight\">{}</code></pre>",
+            ansi::to_html(text)
+        ),
+    }
+}
+
+/// Wraps text into a fenced code block. The fence is longer than any run
+/// of backticks in the text, so source code with doc-test fences fits.
+pub fn fence(language: &str, text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{language}\n{text}\n{fence}")
+}
+
+/// A one-line summary of how cargo exited, how long it took and which
+/// toolchain it ran with.
+fn footer(output: &StageOutput, style: &Style) -> String {
+    let mut footer = status(output);
+    if style.timings {
+        let _ = write!(footer, " · {:.2} s", output.duration.as_secs_f64());
+    }
+    if !output.toolchain.is_empty() {
+        footer.push_str(" · ");
+        footer.push_str(&output.toolchain);
+    }
+    format!("*{footer}*")
+}
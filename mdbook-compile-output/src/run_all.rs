@@ -0,0 +1,261 @@
+//! The `run-all` subcommand: runs every stage of a book outside of a book
+//! build and reports whether each ended the way the book expects.
+//!
+//! The stages are those the chapters' directives run, plus the stages below
+//! `stage-root` matching the `stages` glob if it is set. They are run the
+//! way the preprocessor runs them, through the same cache, and every run
+//! leaves a normalized log in `log-dir`.
+
+use crate::artifacts;
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::directive::Options;
+use crate::stage::{Expect, Job, StageOutput};
+use crate::{ansi, cargo_args, execute, find_directives, offline, pool, shared_target};
+use mdbook::MDBook;
+use mdbook::book::BookItem;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const USAGE: &str = "usage: mdbook-compile-output run-all [--book <dir>]";
+
+/// A job and every outcome the book expects of it.
+struct Entry {
+    job: Job,
+    expects: Vec<Expect>,
+}
+
+/// Runs the subcommand with the arguments following `run-all`. Returns
+/// whether every stage ended as expected.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<bool, Error> {
+    let mut book_dir = PathBuf::from(".");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--book" => {
+                book_dir = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| Error::msg(format!("--book needs a directory\n{USAGE}")))?;
+            }
+            _ => return Err(Error::msg(format!("unknown argument: {arg}\n{USAGE}"))),
+        }
+    }
+
+    let book = MDBook::load(&book_dir)
+        .map_err(|e| e.context(format!("failed to load the book in {}", book_dir.display())))?;
+    let root = book.root.canonicalize().unwrap_or(book.root.clone());
+    // Not rendering anything, so no renderer gets colored output.
+    let ctx: PreprocessorContext = serde_json::from_value(serde_json::json!({
+        "root": root,
+        "config": book.config,
+        "renderer": "run-all",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    }))?;
+    let config = Config::from_context(&ctx)?;
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut add = |job: Job, expect: Expect| match entries.iter_mut().find(|e| e.job == job) {
+        Some(entry) => entry.expects.push(expect),
+        None => entries.push(Entry {
+            job,
+            expects: vec![expect],
+        }),
+    };
+    for item in book.iter() {
+        let BookItem::Chapter(ch) = item else {
+            continue;
+        };
+        if ch.is_draft_chapter() {
+            continue;
+        }
+        for directive in find_directives(ch, &ctx, &config)? {
+            if let Some(job) = directive.job {
+                add(job, directive.options.expect.unwrap_or(config.expect));
+            }
+        }
+    }
+    if let Some(glob) = &config.stages {
+        for path in matching_stages(&ctx.root.join(&config.stage_root), glob)? {
+            add(default_job(path, &ctx, &config), config.expect);
+        }
+    }
+    if entries.is_empty() {
+        return Err(Error::msg(format!(
+            "no stages to run in {}",
+            ctx.root.display()
+        )));
+    }
+
+    let cache = Cache::open(ctx.root.join(&config.cache_dir), &ctx.root, config.cache)?;
+    let jobs: Vec<&Job> = entries.iter().map(|entry| &entry.job).collect();
+    if config.offline {
+        offline::check(&jobs, &ctx.root)?;
+    }
+    if config.warm {
+        shared_target::warm_up(&jobs, &cache)?;
+    }
+    let scratch = ctx.root.join(&config.scratch_dir);
+    let numbered: Vec<(usize, &Entry)> = entries.iter().enumerate().collect();
+    let outputs = pool::map(&numbered, config.jobs(), |(number, entry)| {
+        eprintln!(
+            "running {} in {}",
+            command_line(&entry.job),
+            entry.job.path.display()
+        );
+        execute(&entry.job, &scratch.join(number.to_string()), &cache)
+    });
+
+    let log_dir = ctx.root.join(&config.log_dir);
+    if log_dir.exists() {
+        fs::remove_dir_all(&log_dir)?;
+    }
+    fs::create_dir_all(&log_dir)
+        .map_err(|e| Error::new(e).context(format!("failed to create {}", log_dir.display())))?;
+
+    let mut rows = Vec::new();
+    let mut all_expected = true;
+    let mut log_names: Vec<String> = Vec::new();
+    for (entry, output) in entries.iter().zip(&outputs) {
+        let job = &entry.job;
+        let stage = job
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut log_name = format!("{stage}_{}.log", job.args.first().map_or("cargo", |a| a));
+        let mut count = 1;
+        while log_names.contains(&log_name) {
+            count += 1;
+            log_name = format!(
+                "{stage}_{}_{count}.log",
+                job.args.first().map_or("cargo", |a| a)
+            );
+        }
+        log_names.push(log_name.clone());
+
+        let (outcome, log) = match output {
+            Ok(output) => (
+                output.outcome().to_string(),
+                log_text(job, output, &ctx, &config),
+            ),
+            Err(e) => (format!("error: {e:#}"), format!("{e:#}\n")),
+        };
+        fs::write(log_dir.join(&log_name), log)?;
+        let expected = match output {
+            Ok(output) => entry.expects.iter().all(|e| e.matches(output.outcome())),
+            Err(_) => false,
+        };
+        all_expected &= expected;
+        let mut expects: Vec<String> = Vec::new();
+        for expect in entry.expects.iter().map(ToString::to_string) {
+            if !expects.contains(&expect) {
+                expects.push(expect);
+            }
+        }
+        rows.push([
+            if expected { "✔" } else { "✘" }.to_string(),
+            stage,
+            command_line(job),
+            expects.join(", "),
+            outcome,
+            config.log_dir.join(&log_name).display().to_string(),
+        ]);
+    }
+    print!(
+        "{}",
+        table(
+            &["", "stage", "command", "expected", "outcome", "log"],
+            &rows
+        )
+    );
+    if !all_expected {
+        let failed = rows.iter().filter(|row| row[0] == "✘").count();
+        eprintln!("{failed} of {} stages did not end as expected", rows.len());
+    }
+    Ok(all_expected)
+}
+
+/// The stage directories below `stage_root` whose names match `glob`.
+fn matching_stages(stage_root: &Path, glob: &str) -> Result<Vec<PathBuf>, Error> {
+    let regex = artifacts::glob_regex(glob)?;
+    let mut stages = Vec::new();
+    for entry in fs::read_dir(stage_root)
+        .map_err(|e| Error::new(e).context(format!("failed to read {}", stage_root.display())))?
+    {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.join("Cargo.toml").is_file() && regex.is_match(&name) {
+            stages.push(path.canonicalize().unwrap_or(path));
+        }
+    }
+    stages.sort();
+    Ok(stages)
+}
+
+/// The job of a directive without options for the stage at `path`.
+fn default_job(path: PathBuf, ctx: &PreprocessorContext, config: &Config) -> Job {
+    Job {
+        path,
+        args: cargo_args(&Options::default(), config),
+        timeout: (config.timeout > 0).then(|| Duration::from_secs(config.timeout)),
+        color: false,
+        artifacts: None,
+        target_dir: config.target_dir(&ctx.root),
+        toolchain: config.toolchain.clone(),
+    }
+}
+
+/// The log of one run: the command, its normalized output and how it ended.
+fn log_text(job: &Job, output: &StageOutput, ctx: &PreprocessorContext, config: &Config) -> String {
+    let text = config
+        .normalizer
+        .apply(&ansi::strip(&output.interleaved), &job.path, &ctx.root);
+    let status = match output.exit_code {
+        _ if output.timed_out => "killed on timeout".to_string(),
+        Some(code) => format!("exit code {code}"),
+        None => "killed by a signal".to_string(),
+    };
+    format!(
+        "$ {}\n{text}\n{status} after {:.2} s with {}\n",
+        command_line(job),
+        output.duration.as_secs_f64(),
+        output.toolchain
+    )
+}
+
+/// The cargo command of a job, with its toolchain the way rustup's `+`
+/// syntax names it.
+fn command_line(job: &Job) -> String {
+    match &job.toolchain {
+        Some(toolchain) => format!("cargo +{toolchain} {}", job.args.join(" ")),
+        None => format!("cargo {}", job.args.join(" ")),
+    }
+}
+
+/// Lays out rows as a plain text table with padded columns.
+fn table<const N: usize>(header: &[&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(|cell| cell.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut result = String::new();
+    let mut line = |cells: Vec<&str>| {
+        for (cell, width) in cells.iter().zip(widths) {
+            let _ = write!(result, "{cell:width$}  ");
+        }
+        let trimmed = result.trim_end().len();
+        result.truncate(trimmed);
+        result.push('\n');
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+    result
+}
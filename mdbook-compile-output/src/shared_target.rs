@@ -0,0 +1,191 @@
+//! The cargo target directory all stages share.
+//!
+//! The stages of a book are copies of one crate at different points in
+//! time, so they share most of their dependencies, and usually their
+//! package name too. Cargo tells the builds of a stage crate apart by a hash
+//! that ignores where the crate lives, which in a shared target directory
+//! would let one stage pick up another's fresh looking build. Every stage
+//! therefore builds through a `RUSTC_WORKSPACE_WRAPPER` of its own: a link
+//! to this executable, which passes the call on to rustc. Cargo mixes the
+//! wrapper path into the hash of the stage crate only, so the dependencies
+//! are still built once for all stages.
+//!
+//! With `warm` set, the dependencies are built before any stage runs, so
+//! the outputs show the stages being built and nothing else.
+
+use crate::cache::Cache;
+use crate::offline;
+use crate::stage::{self, Job};
+use mdbook::errors::Error;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Set for cargo, so the executable knows it runs as a stage's wrapper.
+const WRAPPER_ENV: &str = "COMPILE_OUTPUT_WRAPPER";
+
+/// Held while a wrapper link is created.
+static LINKING: Mutex<()> = Mutex::new(());
+
+/// Passes a call as a rustc wrapper on to rustc and returns its exit code,
+/// or `None` if the executable was not started as a wrapper.
+pub fn wrapped_rustc() -> Option<i32> {
+    std::env::var_os(WRAPPER_ENV)?;
+    let mut args = std::env::args_os().skip(1);
+    let rustc = args.next()?;
+    let status = Command::new(&rustc).args(args).status();
+    Some(match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("failed to run {}: {e}", rustc.to_string_lossy());
+            1
+        }
+    })
+}
+
+/// Points a cargo command in the stage at `path` to the shared target
+/// directory, with the stage's own wrapper.
+pub fn configure(command: &mut Command, target_dir: &Path, path: &Path) -> Result<(), Error> {
+    command
+        .env("CARGO_TARGET_DIR", target_dir)
+        .env("RUSTC_WORKSPACE_WRAPPER", wrapper(target_dir, path)?)
+        .env(WRAPPER_ENV, "1");
+    Ok(())
+}
+
+/// The wrapper of the stage at `path`, created if it is not there yet.
+fn wrapper(target_dir: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let exe = std::env::current_exe()
+        .map_err(|e| Error::new(e).context("failed to find the preprocessor executable"))?;
+    let digest = Sha256::digest(path.to_string_lossy().as_bytes());
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let hash: String = digest.iter().take(8).map(|b| format!("{b:02x}")).collect();
+    let dir = target_dir
+        .join("compile-output")
+        .join(format!("{name}-{hash}"));
+    let wrapper = dir.join(exe.file_name().unwrap_or_default());
+
+    let _linking = LINKING.lock().unwrap_or_else(|e| e.into_inner());
+    if is_link_to(&wrapper, &exe) {
+        return Ok(wrapper);
+    }
+    fs::create_dir_all(&dir)
+        .map_err(|e| Error::new(e).context(format!("failed to create {}", dir.display())))?;
+    if fs::symlink_metadata(&wrapper).is_ok() {
+        fs::remove_file(&wrapper)?;
+    }
+    link(&exe, &wrapper).map_err(|e| {
+        Error::new(e).context(format!(
+            "failed to create the wrapper {}",
+            wrapper.display()
+        ))
+    })?;
+    Ok(wrapper)
+}
+
+#[cfg(unix)]
+fn is_link_to(wrapper: &Path, exe: &Path) -> bool {
+    fs::read_link(wrapper).is_ok_and(|target| target == exe)
+}
+
+#[cfg(unix)]
+fn link(exe: &Path, wrapper: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(exe, wrapper)
+}
+
+// Without symlinks the wrapper is a copy, renewed whenever the executable
+// changes size.
+#[cfg(not(unix))]
+fn is_link_to(wrapper: &Path, exe: &Path) -> bool {
+    let size = |path: &Path| fs::metadata(path).map(|m| m.len()).ok();
+    size(wrapper).is_some() && size(wrapper) == size(exe)
+}
+
+#[cfg(not(unix))]
+fn link(exe: &Path, wrapper: &Path) -> std::io::Result<()> {
+    fs::hard_link(exe, wrapper).or_else(|_| fs::copy(exe, wrapper).map(|_| ()))
+}
+
+/// Builds the dependencies of every stage with a job the cache cannot
+/// answer, one stage after the other. A failing warm-up is reported and
+/// otherwise ignored; the stage run that follows shows what went wrong.
+pub fn warm_up(jobs: &[&Job], cache: &Cache) -> Result<(), Error> {
+    let mut stages: Vec<(&Path, Vec<&str>, &Path, &Job)> = Vec::new();
+    for job in jobs {
+        let Some(target_dir) = &job.target_dir else {
+            continue;
+        };
+        // The arguments deciding which builds of the dependencies the job
+        // uses and whether cargo may fetch them.
+        let flags = job
+            .args
+            .iter()
+            .map(String::as_str)
+            .filter(|arg| ["--release", "-r"].contains(arg) || offline::ARGS.contains(arg))
+            .collect();
+        let known = stages.iter().any(|(path, known_flags, _, other)| {
+            *path == job.path && *known_flags == flags && other.toolchain == job.toolchain
+        });
+        if !known && !cache.has(job)? {
+            stages.push((&job.path, flags, target_dir, job));
+        }
+    }
+    for (path, flags, target_dir, job) in stages {
+        let dependencies = dependencies(path)?;
+        if dependencies.is_empty() {
+            continue;
+        }
+        eprintln!("building the dependencies of {}", path.display());
+        let mut command = Command::new("cargo");
+        command
+            .arg("build")
+            .args(flags)
+            .current_dir(path)
+            .stdin(Stdio::null());
+        for dependency in &dependencies {
+            command.args(["-p", dependency]);
+        }
+        configure(&mut command, target_dir, path)?;
+        stage::use_toolchain(&mut command, job);
+        let output = command.output().map_err(|e| {
+            Error::new(e).context(format!("failed to run cargo in {}", path.display()))
+        })?;
+        if !output.status.success() {
+            eprintln!(
+                "warning: building the dependencies of {} failed:\n{}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The names of the non-optional packages in the `[dependencies]` and
+/// `[dev-dependencies]` of a stage's manifest.
+fn dependencies(stage: &Path) -> Result<Vec<String>, Error> {
+    let path = stage.join("Cargo.toml");
+    let manifest: toml::Value = fs::read_to_string(&path)
+        .map_err(Error::new)
+        .and_then(|text| text.parse().map_err(Error::new))
+        .map_err(|e| e.context(format!("failed to read {}", path.display())))?;
+    let mut names = Vec::new();
+    for table in ["dependencies", "dev-dependencies"] {
+        let Some(dependencies) = manifest.get(table).and_then(|t| t.as_table()) else {
+            continue;
+        };
+        for (name, spec) in dependencies {
+            if spec.get("optional").and_then(|o| o.as_bool()) == Some(true) {
+                continue;
+            }
+            // A renamed dependency is built under its package name.
+            let package = spec.get("package").and_then(|p| p.as_str()).unwrap_or(name);
+            if !names.iter().any(|known| known == package) {
+                names.push(package.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,110 @@
+//! Golden snapshots of rendered directive output.
+//!
+//! Snapshots live next to the chapter, one Markdown file per directive in a
+//! `<chapter>.snapshots/` directory, named after the position of the
+//! directive in the chapter and its stage. mdbook does not copy `.md` files
+//! into the rendered book, so the snapshots stay out of the output.
+
+use mdbook::book::Chapter;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What the preprocessor does with snapshots, set with the `snapshots` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotMode {
+    /// Ignore them.
+    #[default]
+    Off,
+    /// Run the stages and write what they rendered to the snapshots.
+    Record,
+    /// Render the snapshots without running cargo at all.
+    Replay,
+    /// Run the stages and fail if anything differs from the snapshots.
+    Verify,
+}
+
+/// The snapshot file of the `number`th directive (counting from 1) in a
+/// chapter.
+pub fn path(ctx: &PreprocessorContext, ch: &Chapter, number: usize, stage: &str) -> PathBuf {
+    let source = ch
+        .source_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(&ch.name));
+    let mut dir = source.file_stem().unwrap_or_default().to_os_string();
+    dir.push(".snapshots");
+    let stage = stage.replace(['/', '\\', '.'], "_");
+    ctx.root
+        .join(&ctx.config.book.src)
+        .join(source.with_file_name(dir))
+        .join(format!("{number:02}-{stage}.md"))
+}
+
+pub fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|e| {
+        Error::new(e).context(format!(
+            "no snapshot in {}, build once with snapshots = \"record\"",
+            path.display()
+        ))
+    })
+}
+
+/// Writes a snapshot unless it already holds `rendered`. Snapshots live in
+/// the book's sources, so rewriting them would make `mdbook serve` rebuild
+/// over and over.
+pub fn write(path: &Path, rendered: &str) -> Result<(), Error> {
+    if fs::read_to_string(path).is_ok_and(|recorded| recorded == rendered) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, rendered)
+        .map_err(|e| Error::new(e).context(format!("failed to write {}", path.display())))
+}
+
+/// Removes snapshots in the directories of `keep` that belong to no
+/// directive any more.
+pub fn prune(keep: &[&Path]) -> Result<(), Error> {
+    let mut dirs: Vec<&Path> = keep.iter().filter_map(|path| path.parent()).collect();
+    dirs.dedup();
+    for dir in dirs.into_iter().filter(|dir| dir.is_dir()) {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !keep.contains(&path.as_path()) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails with the first differing line if `rendered` is not what the
+/// snapshot holds.
+pub fn verify(path: &Path, rendered: &str) -> Result<(), Error> {
+    let recorded = read(path)?;
+    if recorded == rendered {
+        return Ok(());
+    }
+    let mut recorded_lines = recorded.lines();
+    let mut rendered_lines = rendered.lines();
+    let mut number = 1;
+    loop {
+        match (recorded_lines.next(), rendered_lines.next()) {
+            (Some(old), Some(new)) if old == new => number += 1,
+            (old, new) => {
+                return Err(Error::msg(format!(
+                    "output differs from the snapshot in {} at line {number}:\n\
+                     - {}\n\
+                     + {}",
+                    path.display(),
+                    old.unwrap_or("<end of snapshot>"),
+                    new.unwrap_or("<end of output>")
+                )));
+            }
+        }
+    }
+}
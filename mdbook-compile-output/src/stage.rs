@@ -0,0 +1,466 @@
+//! Running cargo inside a stage directory.
+
+use crate::ansi;
+use crate::artifacts;
+use crate::shared_target;
+use mdbook::errors::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One cargo invocation. Directives asking for the same job share its output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// The stage directory cargo runs in.
+    pub path: PathBuf,
+    /// The arguments passed to cargo.
+    pub args: Vec<String>,
+    /// How long cargo may run before it is killed.
+    pub timeout: Option<Duration>,
+    /// Whether cargo is asked for colored output.
+    pub color: bool,
+    /// The glob of files to collect from the scratch directory, if the job
+    /// gets one.
+    pub artifacts: Option<String>,
+    /// The target directory shared with other stages, if any.
+    pub target_dir: Option<PathBuf>,
+    /// The rustup toolchain cargo runs with, if not the default one.
+    pub toolchain: Option<String>,
+}
+
+/// Everything a cargo invocation left behind that we may want to render.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+    /// Set when cargo was killed because the job ran into its timeout.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Both streams, in the order their chunks were read. The streams are
+    /// read on threads of their own, so this is close to the order cargo
+    /// wrote them in, but not exact, so nothing relies on that order.
+    #[serde(default)]
+    pub interleaved: String,
+    /// The exit code, if cargo exited normally.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// How long cargo ran.
+    #[serde(default)]
+    pub duration: Duration,
+    /// The files matching the job's `artifacts` glob, relative to
+    /// `artifact_dir`.
+    #[serde(default)]
+    pub artifacts: Vec<PathBuf>,
+    /// Where the artifacts are: the scratch directory after a run, the
+    /// cache after a cache hit.
+    #[serde(skip)]
+    pub artifact_dir: PathBuf,
+    /// The `rustc -V` of the toolchain cargo ran with.
+    #[serde(default)]
+    pub toolchain: String,
+}
+
+impl StageOutput {
+    /// Tells how cargo ended, telling build failures from failing tests by
+    /// the messages cargo prints for them.
+    pub fn outcome(&self) -> Outcome {
+        let stderr = ansi::strip(&self.stderr);
+        if self.timed_out {
+            Outcome::TimedOut
+        } else if self.success {
+            Outcome::Success
+        } else if stderr.contains("error: could not compile") {
+            Outcome::CompileError
+        } else if stderr.contains("error: test failed")
+            || stderr.contains("error: doctest failed")
+            || ansi::strip(&self.stdout).contains("test result: FAILED")
+        {
+            Outcome::TestFailure
+        } else {
+            Outcome::Failure
+        }
+    }
+}
+
+/// How a cargo invocation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    CompileError,
+    TestFailure,
+    /// Any other non-zero exit, e.g. a binary started by `cargo run` failed.
+    Failure,
+    TimedOut,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Success => "success",
+            Outcome::CompileError => "a compile error",
+            Outcome::TestFailure => "a test failure",
+            Outcome::Failure => "a failure",
+            Outcome::TimedOut => "a timeout",
+        })
+    }
+}
+
+/// The outcome a directive declares with `expect=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Expect {
+    /// Accept whatever happens.
+    #[default]
+    Any,
+    Success,
+    /// Any kind of failure.
+    Fail,
+    CompileError,
+    TestFailure,
+}
+
+impl Expect {
+    pub fn matches(self, outcome: Outcome) -> bool {
+        match self {
+            Expect::Any => true,
+            Expect::Success => outcome == Outcome::Success,
+            Expect::Fail => outcome != Outcome::Success,
+            Expect::CompileError => outcome == Outcome::CompileError,
+            Expect::TestFailure => outcome == Outcome::TestFailure,
+        }
+    }
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Expect::Any => "any outcome",
+            Expect::Success => "success",
+            Expect::Fail => "a failure",
+            Expect::CompileError => "a compile error",
+            Expect::TestFailure => "a test failure",
+        })
+    }
+}
+
+/// Held by a `cargo run` job in a shared target directory.
+static SHARED_RUN: Mutex<()> = Mutex::new(());
+
+/// Runs cargo in the job's directory and waits for it to finish, or for
+/// the job's timeout to expire. With a scratch directory `out`, `{out}` in
+/// the arguments and `COMPILE_OUTPUT_DIR` point there.
+pub fn run_cargo(job: &Job, out: Option<&Path>) -> Result<StageOutput, Error> {
+    let path = &job.path;
+    let toolchain = rustc(job, "-V")?.trim().to_string();
+    let mut command = Command::new("cargo");
+    use_toolchain(&mut command, job);
+    match out {
+        Some(out) => {
+            let out = out.to_string_lossy();
+            command
+                .args(
+                    job.args
+                        .iter()
+                        .map(|arg| arg.replace(artifacts::PLACEHOLDER, &out)),
+                )
+                .env(artifacts::ENV, &*out);
+        }
+        None => {
+            command.args(&job.args);
+        }
+    }
+    command
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if job.color {
+        command.env("CARGO_TERM_COLOR", "always");
+    }
+    // `cargo run` starts the binary from the top of the target directory,
+    // where stages with the same package name overwrite each other's, so
+    // only one such job runs at a time in a shared target directory.
+    let _running = match &job.target_dir {
+        Some(target_dir) => {
+            shared_target::configure(&mut command, target_dir, path)?;
+            (job.args.first().is_some_and(|arg| arg == "run"))
+                .then(|| SHARED_RUN.lock().unwrap_or_else(|e| e.into_inner()))
+        }
+        None => None,
+    };
+    #[cfg(unix)]
+    if job.timeout.is_some() {
+        // Lead a process group of our own, so a timeout can take down
+        // rustc and the test binaries together with cargo.
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let start = Instant::now();
+    let mut child = command.spawn().map_err(|e| spawn_error(e, path))?;
+
+    let chunks = Chunks::default();
+    let readers = [
+        read_in_background(child.stdout.take(), Stream::Stdout, &chunks),
+        read_in_background(child.stderr.take(), Stream::Stderr, &chunks),
+    ];
+
+    let status = match job.timeout {
+        Some(timeout) => wait_with_timeout(&mut child, timeout),
+        None => child.wait().map(Some),
+    }
+    .map_err(|e| spawn_error(e, path))?;
+    let duration = start.elapsed();
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    let chunks = chunks.lock().unwrap();
+    let collect = |stream: Option<Stream>| {
+        let bytes: Vec<u8> = chunks
+            .iter()
+            .filter(|(from, _)| stream.is_none_or(|stream| stream == *from))
+            .flat_map(|(_, bytes)| bytes.iter().copied())
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    Ok(StageOutput {
+        success: status.is_some_and(|status| status.success()),
+        stdout: collect(Some(Stream::Stdout)),
+        stderr: collect(Some(Stream::Stderr)),
+        timed_out: status.is_none(),
+        interleaved: collect(None),
+        exit_code: status.and_then(|status| status.code()),
+        duration,
+        artifacts: Vec::new(),
+        artifact_dir: PathBuf::new(),
+        toolchain,
+    })
+}
+
+/// Makes a command run with the job's toolchain. `RUSTUP_TOOLCHAIN`
+/// rather than `cargo +toolchain`, so the rustc that cargo starts, and
+/// anything else the command runs, uses it as well.
+pub fn use_toolchain(command: &mut Command, job: &Job) {
+    if let Some(toolchain) = &job.toolchain {
+        command.env("RUSTUP_TOOLCHAIN", toolchain);
+    }
+}
+
+/// What `rustc <arg>` prints in the stage directory with the job's
+/// toolchain, so a `rust-toolchain.toml` inside the stage counts as well.
+pub fn rustc(job: &Job, arg: &str) -> Result<String, Error> {
+    let mut command = Command::new("rustc");
+    command.arg(arg).current_dir(&job.path);
+    use_toolchain(&mut command, job);
+    let output = command
+        .output()
+        .map_err(|e| Error::new(e).context(format!("failed to run rustc {arg}")))?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "rustc {arg} failed in {}:\n{}",
+            job.path.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// What was read from either pipe, in the order it arrived.
+type Chunks = Arc<Mutex<Vec<(Stream, Vec<u8>)>>>;
+
+fn spawn_error(e: std::io::Error, path: &Path) -> Error {
+    Error::new(e).context(format!("failed to run cargo in {}", path.display()))
+}
+
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+    stream: Stream,
+    chunks: &Chunks,
+) -> thread::JoinHandle<()> {
+    let chunks = Arc::clone(chunks);
+    thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut buffer = [0; 8192];
+        while let Ok(read @ 1..) = pipe.read(&mut buffer) {
+            chunks
+                .lock()
+                .unwrap()
+                .push((stream, buffer[..read].to_vec()));
+        }
+    })
+}
+
+/// Waits for the child, killing its whole process group once `timeout` has
+/// passed. Returns `None` if it had to be killed.
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Duration,
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            kill_tree(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    // SAFETY: kill(2) has no memory-safety preconditions. The negative pid
+    // addresses the process group the child leads.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(success: bool, stdout: &str, stderr: &str) -> StageOutput {
+        StageOutput {
+            success,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            timed_out: false,
+            interleaved: format!("{stderr}{stdout}"),
+            exit_code: Some(if success { 0 } else { 101 }),
+            duration: Duration::ZERO,
+            artifacts: Vec::new(),
+            artifact_dir: PathBuf::new(),
+            toolchain: String::new(),
+        }
+    }
+
+    #[test]
+    fn tells_outcomes_apart() {
+        for (stdout, stderr, expected) in [
+            (
+                "",
+                "   Compiling step1 v0.1.0 (rust_stages/step1)
+error[E0425]: cannot find value `x` in this scope
+ --> src/bin/x.rs:1:20
+
+For more information about this error, try `rustc --explain E0425`.
+error: could not compile `step1` (bin \"x\") due to 1 previous error
+",
+                Outcome::CompileError,
+            ),
+            (
+                "test tests::scale ... FAILED\n\n\
+                 test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out\n",
+                "    Finished `test` profile [unoptimized + debuginfo] target(s) in 0.71s
+     Running unittests src/lib.rs (target/debug/deps/step1-55b08702f42bedb7)
+error: test failed, to rerun pass `--lib`
+",
+                Outcome::TestFailure,
+            ),
+            (
+                "",
+                "    Finished `test` profile [unoptimized + debuginfo] target(s) in 0.14s
+   Doc-tests step1
+error: doctest failed, to rerun pass `--doc`
+",
+                Outcome::TestFailure,
+            ),
+            (
+                "\nrunning 1 test\n",
+                "error: test failed, to rerun pass `--lib`
+
+Caused by:
+  process didn't exit successfully: `target/debug/deps/step1-55b08702f42bedb7` \
+                 (signal: 11, SIGSEGV: invalid memory reference)
+",
+                Outcome::TestFailure,
+            ),
+            (
+                "",
+                "    Finished `dev` profile [unoptimized + debuginfo] target(s) in 0.10s
+     Running `target/debug/step4 -f missing.tsv`
+error: process didn't exit successfully: `target/debug/step4 -f missing.tsv` (exit status: 1)
+",
+                Outcome::Failure,
+            ),
+            (
+                "",
+                "error: failed to run custom build command for `step1 v0.1.0 (rust_stages/step1)`
+
+Caused by:
+  process didn't exit successfully: `target/debug/build/step1-0123456789abcdef/build-script-build` \
+                 (exit status: 1)
+",
+                Outcome::Failure,
+            ),
+        ] {
+            assert_eq!(
+                output(false, stdout, stderr).outcome(),
+                expected,
+                "{stderr}"
+            );
+        }
+    }
+
+    #[test]
+    fn colors_do_not_hide_the_outcome() {
+        let stderr = "\x1b[1m\x1b[91merror\x1b[0m\x1b[1m:\x1b[0m could not compile `step1`";
+        assert_eq!(output(false, "", stderr).outcome(), Outcome::CompileError);
+    }
+
+    #[test]
+    fn success_and_timeouts_win_over_messages() {
+        let stderr = "error: test failed, to rerun pass `--lib`";
+        assert_eq!(output(true, "", stderr).outcome(), Outcome::Success);
+        let mut timed_out = output(false, "", stderr);
+        timed_out.timed_out = true;
+        assert_eq!(timed_out.outcome(), Outcome::TimedOut);
+    }
+
+    #[test]
+    fn expectations_match_outcomes() {
+        use Outcome::*;
+        let all = [Success, CompileError, TestFailure, Failure, TimedOut];
+        for (expect, matching) in [
+            (Expect::Any, &all[..]),
+            (Expect::Success, &[Success][..]),
+            (
+                Expect::Fail,
+                &[CompileError, TestFailure, Failure, TimedOut][..],
+            ),
+            (Expect::CompileError, &[CompileError][..]),
+            (Expect::TestFailure, &[TestFailure][..]),
+        ] {
+            for outcome in all {
+                assert_eq!(
+                    expect.matches(outcome),
+                    matching.contains(&outcome),
+                    "{expect} vs {outcome}"
+                );
+            }
+        }
+    }
+}